@@ -0,0 +1,73 @@
+extern crate yetanotherlisp;
+
+use std::time::Instant;
+
+use yetanotherlisp::cell::{Cell, init_storage};
+use yetanotherlisp::env::{Env, DefaultNS};
+use yetanotherlisp::eval::eval;
+use yetanotherlisp::parser::Parser;
+use yetanotherlisp::vm;
+
+const ITERATIONS: u32 = 100_000;
+
+const WORKLOADS: [(&str, &str); 3] =
+    [("nested arithmetic", "(add (mul (sub 10 4) (div 9 3)) (mod (add 17 5) (mul 2 3)))"),
+     ("list construction", "(cons 1 (cons 2 (cons 3 (cons 4 (cons 5 ())))))"),
+     ("list mapping", "(map2 add ('(1 2 3 4 5 6 7 8)) ('(8 7 6 5 4 3 2 1)))")];
+
+/// Parses and evaluates `input` repeatedly, returning expressions per second.
+fn run(input: &str, env: &mut Env, ns: &DefaultNS) -> f64 {
+    let buf = input.as_bytes().to_vec();
+    let mut cells = [Cell::empty(); 256];
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        // Evaluation doesn't give back its intermediate cells, so each
+        // iteration starts over with a fresh free list.
+        let mut storage = init_storage(&mut cells);
+        let (exp, _) = Parser::new(env).parse(&buf, &mut storage).unwrap();
+        eval(exp, &mut storage, env, ns).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    ITERATIONS as f64 / elapsed.as_secs_f64()
+}
+
+/// Evaluates the already parsed arithmetic `input` repeatedly, by walking
+/// the tree and then by running its bytecode, returning expressions per
+/// second for each. Every value along the way is a small interned number,
+/// so neither takes any cells and the storage never needs renewing.
+fn compare_vm(input: &str, env: &mut Env, ns: &DefaultNS) -> (f64, f64) {
+    let buf = input.as_bytes().to_vec();
+    let mut cells = [Cell::empty(); 256];
+    let mut storage = init_storage(&mut cells);
+    let (exp, _) = Parser::new(env).parse(&buf, &mut storage).unwrap();
+    let code = vm::compile(exp, &storage, ns).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        eval(exp, &mut storage, env, ns).unwrap();
+    }
+    let walked = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        vm::run(&code, &mut storage, ns).unwrap();
+    }
+    let compiled = start.elapsed();
+
+    (ITERATIONS as f64 / walked.as_secs_f64(), ITERATIONS as f64 / compiled.as_secs_f64())
+}
+
+fn main() {
+    let mut env = Env::new();
+    let ns = DefaultNS::new(&mut env);
+
+    for &(name, input) in WORKLOADS.iter() {
+        println!("{:<20} {:>12.0} exprs/sec", name, run(input, &mut env, &ns));
+    }
+
+    let (walked, compiled) = compare_vm(WORKLOADS[0].1, &mut env, &ns);
+    println!("{:<20} {:>12.0} exprs/sec", "parsed, tree-walked", walked);
+    println!("{:<20} {:>12.0} exprs/sec", "parsed, bytecode", compiled);
+}
@@ -1,14 +1,31 @@
-use env::{Env, SymbolIndex};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Write;
+
+use env::{Env, HashTableIndex, PromiseIndex, SymbolIndex, VectorIndex};
 
 pub type CellIndex = usize;
 
 pub const NIL_INDEX: CellIndex = 0;
 
+/// Numbers in `SMALL_INT_MIN..SMALL_INT_END` are interned: `alloc_cell`
+/// hands out the same read-only cell for each, which lives past the end of
+/// the buffer and never touches the free list.
+pub const SMALL_INT_MIN: i32 = -8;
+pub const SMALL_INT_END: i32 = 256;
+
 #[derive(Copy, Clone, Debug)]
 pub enum CellType {
     Number(i32),
     Symbol(SymbolIndex),
     Cons(CellIndex),
+    /// A vector, whose elements are kept in the `Env`.
+    Vector(VectorIndex),
+    /// A hash table, whose entries are kept in the `Env`.
+    HashTable(HashTableIndex),
+    /// A delayed expression, kept in the `Env`.
+    Promise(PromiseIndex),
     Free,
 }
 
@@ -26,8 +43,8 @@ impl Cell {
     }
     fn new(val: CellType, tail: CellIndex) -> Self {
         Cell {
-            val: val,
-            tail: tail,
+            val,
+            tail,
         }
     }
 }
@@ -36,54 +53,171 @@ impl Cell {
 pub struct CellStorage<'a> {
     free_index: CellIndex,
     cells: &'a mut [Cell],
+    /// The cells allocated since each `start_tracking` that hasn't been
+    /// stopped yet, innermost last.
+    tracked: Vec<Vec<CellIndex>>,
 }
 impl<'a> CellStorage<'a> {
-    pub fn new(buf: &mut [Cell]) -> CellStorage {
+    pub fn new(buf: &mut [Cell]) -> CellStorage<'_> {
         CellStorage {
             cells: buf,
             free_index: NIL_INDEX + 1,
+            tracked: Vec::new(),
         }
     }
     pub fn alloc_cell(&mut self, val: CellType) -> CellIndex {
+        match self.try_alloc_cell(val) {
+            Some(idx) => idx,
+            None => panic!("Exhausted cell storage!"),
+        }
+    }
+    /// Like `alloc_cell`, but gives `None` rather than panicking when no
+    /// cells are left.
+    pub fn try_alloc_cell(&mut self, val: CellType) -> Option<CellIndex> {
+        if let CellType::Number(n) = val {
+            if (SMALL_INT_MIN..SMALL_INT_END).contains(&n) {
+                return Some(self.cells.len() + (n - SMALL_INT_MIN) as usize);
+            }
+        }
         if self.free_index == NIL_INDEX {
-            panic!("Exhausted cell storage!");
+            None
         } else {
             let idx = self.free_index;
             self.free_index = self.cells[idx].tail;
             self.cells[idx].val = val;
             self.cells[idx].tail = NIL_INDEX;
-            idx
+            if let Some(tracked) = self.tracked.last_mut() {
+                tracked.push(idx);
+            }
+            Some(idx)
         }
     }
-    pub fn free_cell(&mut self, idx: CellIndex) {
+    /// Frees `idx` and everything reachable from it, retiring the `env`
+    /// entries of any vectors, hash tables and promises among them.
+    pub fn free_cell(&mut self, idx: CellIndex, env: &mut Env) {
+        if self.is_interned(idx) {
+            return;
+        }
+        if let Some(ref mut spans) = env.spans {
+            spans.remove(idx);
+        }
+        env.forget_folded(idx);
         match self.cells[idx].val {
-            CellType::Number(_) | CellType::Symbol(_) => {
-                self.cells[idx] = Cell::new(CellType::Free, self.free_index);
-                self.free_index = idx;
-            }
+            CellType::Number(_) | CellType::Symbol(_) => self.release(idx),
             CellType::Cons(head) => {
-                self.free_cell(head);
+                self.free_cell(head, env);
                 let tail = self.cells[idx].tail;
-                self.free_cell(tail);
-                self.cells[idx] = Cell::new(CellType::Free, self.free_index);
-                self.free_index = idx;
+                self.free_cell(tail, env);
+                self.release(idx);
+            }
+            CellType::Vector(vec) => {
+                // Released first, so that a vector holding itself is freed once.
+                self.release(idx);
+                for item in env.remove_vector(vec) {
+                    self.free_cell(item, env);
+                }
+            }
+            CellType::HashTable(table) => {
+                self.release(idx);
+                for val in env.remove_hash_table(table) {
+                    self.free_cell(val, env);
+                }
+            }
+            CellType::Promise(promise) => {
+                self.release(idx);
+                let promise = env.remove_promise(promise);
+                self.free_cell(promise.expr, env);
+                if let Some(value) = promise.value {
+                    self.free_cell(value, env);
+                }
             }
             _ => {}
         }
     }
+    /// Returns a single cell to the free list, leaving alone any cells it
+    /// points to. Interned numbers are left where they are.
+    pub fn release(&mut self, idx: CellIndex) {
+        if !self.is_interned(idx) {
+            self.cells[idx] = Cell::new(CellType::Free, self.free_index);
+            self.free_index = idx;
+        }
+    }
+    /// Whether `idx` is one of the shared small number cells, which can't
+    /// be changed or freed.
+    pub fn is_interned(&self, idx: CellIndex) -> bool {
+        idx >= self.cells.len()
+    }
 
     pub fn get(&self, idx: CellIndex) -> Cell {
-        self.cells[idx]
+        if self.is_interned(idx) {
+            let n = (idx - self.cells.len()) as i32 + SMALL_INT_MIN;
+            Cell::new(CellType::Number(n), NIL_INDEX)
+        } else {
+            self.cells[idx]
+        }
+    }
+    pub fn set_val(&mut self, idx: CellIndex, val: CellType) {
+        assert!(!self.is_interned(idx), "Can't change an interned number!");
+        self.cells[idx].val = val;
     }
     pub fn set_tail(&mut self, idx: CellIndex, tail: CellIndex) {
+        assert!(!self.is_interned(idx), "Can't change an interned number!");
         self.cells[idx].tail = tail;
     }
     pub fn val_of(&self, idx: CellIndex) -> CellType {
-        self.cells[idx].val
+        self.get(idx).val
     }
     pub fn tail_of(&self, idx: CellIndex) -> CellIndex {
-        self.cells[idx].tail
+        self.get(idx).tail
+    }
+    pub fn capacity(&self) -> usize {
+        self.cells.len()
+    }
+    /// The number of cells on the free list.
+    pub fn free_count(&self) -> usize {
+        let mut count = 0;
+        let mut idx = self.free_index;
+        while idx != NIL_INDEX {
+            count += 1;
+            idx = self.cells[idx].tail;
+        }
+        count
+    }
+    /// Whether at least `n` cells are free, without counting all of them.
+    pub fn has_free(&self, n: usize) -> bool {
+        let mut idx = self.free_index;
+        for _ in 0..n {
+            if idx == NIL_INDEX {
+                return false;
+            }
+            idx = self.cells[idx].tail;
+        }
+        true
+    }
+    /// Starts recording every cell that gets allocated. Recordings nest.
+    pub fn start_tracking(&mut self) {
+        self.tracked.push(Vec::new());
+    }
+    /// Stops the innermost recording, returning the cells allocated since
+    /// its `start_tracking`. Some may have been released again since. An
+    /// enclosing recording gets them too.
+    pub fn stop_tracking(&mut self) -> Vec<CellIndex> {
+        let allocated = self.tracked.pop().unwrap_or_default();
+        if let Some(outer) = self.tracked.last_mut() {
+            outer.extend_from_slice(&allocated);
+        }
+        allocated
+    }
+}
+
+/// Creates storage over `buf` with every cell but `NIL_INDEX` on the free list.
+pub fn init_storage(buf: &mut [Cell]) -> CellStorage<'_> {
+    let count = buf.len() - 1;
+    let mut storage = CellStorage::new(buf);
+    for idx in 1..count {
+        storage.set_tail(idx, idx + 1);
     }
+    storage
 }
 
 macro_rules! car {
@@ -107,10 +241,7 @@ macro_rules! cdr {
 }
 
 pub fn is_cons(exp: CellIndex, cells: &CellStorage) -> bool {
-    match cells.val_of(exp) {
-        CellType::Cons(_) => true,
-        _ => false,
-    }
+    matches!(cells.val_of(exp), CellType::Cons(_))
 }
 
 pub fn is_unary(exp: CellIndex, cells: &CellStorage) -> bool {
@@ -122,39 +253,431 @@ pub fn is_binary(exp: CellIndex, cells: &CellStorage) -> bool {
     (cdr!(cdr!(cdr!(exp, cells), cells), cells) == NIL_INDEX)
 }
 
+pub fn is_ternary(exp: CellIndex, cells: &CellStorage) -> bool {
+    is_binary(cdr!(exp, cells), cells)
+}
+
 pub fn print_exp(idx: CellIndex, storage: &CellStorage, env: &Env) {
-    if idx == NIL_INDEX {
-        print!("()");
+    print!("{}", exp_to_string(idx, storage, env));
+}
+
+pub fn exp_to_string(idx: CellIndex, storage: &CellStorage, env: &Env) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail.
+    write_exp(&mut out, idx, storage, env).unwrap();
+    out
+}
+
+fn write_number<W: Write>(out: &mut W, n: i32, radix: u32) -> fmt::Result {
+    let sign = if n < 0 { "-" } else { "" };
+    let magnitude = n.unsigned_abs();
+    match radix {
+        2 => write!(out, "{}0b{:b}", sign, magnitude),
+        8 => write!(out, "{}0o{:o}", sign, magnitude),
+        16 => write!(out, "{}0x{:x}", sign, magnitude),
+        _ => write!(out, "{}", n),
+    }
+}
+
+/// A pending piece of output for `write_exp`.
+enum PrintTask {
+    Exp(CellIndex),
+    /// What follows an element of a list: the rest of its elements, a
+    /// dotted tail, or just the closing parenthesis.
+    Rest(CellIndex),
+    Text(&'static str),
+}
+
+/// The conses reachable from `idx` more than once, for `print_circle`.
+fn shared_conses(idx: CellIndex, storage: &CellStorage, env: &Env) -> HashSet<CellIndex> {
+    let mut seen = HashSet::new();
+    let mut shared = HashSet::new();
+    let mut pending = vec![idx];
+    while let Some(idx) = pending.pop() {
+        match storage.val_of(idx) {
+            CellType::Cons(head) => {
+                if seen.insert(idx) {
+                    pending.push(storage.tail_of(idx));
+                    pending.push(head);
+                } else {
+                    shared.insert(idx);
+                }
+            }
+            CellType::Vector(vec) => pending.extend(env.get_vector(vec)),
+            _ => {}
+        }
+    }
+    shared
+}
+
+/// Works from an explicit stack of tasks rather than recursing, so that
+/// deeply nested structures can't overflow the call stack.
+fn write_exp<W: Write>(out: &mut W,
+                       idx: CellIndex,
+                       storage: &CellStorage,
+                       env: &Env)
+                       -> fmt::Result {
+    let shared = if env.print_circle {
+        shared_conses(idx, storage, env)
     } else {
-        match storage.get(idx).val {
-            CellType::Symbol(sym) => {
-                print!("{}", env.get_sym(sym));
+        HashSet::new()
+    };
+    let mut labels = HashMap::new();
+    let mut tasks = vec![PrintTask::Exp(idx)];
+    while let Some(task) = tasks.pop() {
+        match task {
+            PrintTask::Text(text) => write!(out, "{}", text)?,
+            PrintTask::Exp(NIL_INDEX) => write!(out, "()")?,
+            PrintTask::Exp(idx) if shared.contains(&idx) => {
+                if let Some(label) = labels.get(&idx) {
+                    write!(out, "#{}#", label)?;
+                    continue;
+                }
+                let label = labels.len();
+                labels.insert(idx, label);
+                write!(out, "#{}=(", label)?;
+                tasks.push(PrintTask::Rest(cdr!(idx, storage)));
+                tasks.push(PrintTask::Exp(car!(idx, storage)));
             }
-            CellType::Number(n) => {
-                print!("{}", n);
+            PrintTask::Exp(idx) => {
+                match storage.get(idx).val {
+                    CellType::Symbol(sym) => write!(out, "{}", env.get_sym(sym))?,
+                    CellType::Number(n) => write_number(out, n, env.radix)?,
+                    CellType::Cons(head) => {
+                        write!(out, "(")?;
+                        tasks.push(PrintTask::Rest(cdr!(idx, storage)));
+                        tasks.push(PrintTask::Exp(head));
+                    }
+                    CellType::Vector(vec) => {
+                        write!(out, "#(")?;
+                        tasks.push(PrintTask::Text(")"));
+                        for (i, &item) in env.get_vector(vec).iter().enumerate().rev() {
+                            tasks.push(PrintTask::Exp(item));
+                            if i > 0 {
+                                tasks.push(PrintTask::Text(" "));
+                            }
+                        }
+                    }
+                    CellType::HashTable(table) => {
+                        write!(out, "#<hash-table {}>", env.get_hash_table(table).len())?
+                    }
+                    CellType::Promise(_) => write!(out, "#<promise>")?,
+                    _ => {}
+                }
+            }
+            PrintTask::Rest(NIL_INDEX) => write!(out, ")")?,
+            PrintTask::Rest(tail) => {
+                // A shared tail has to be written dotted to carry its label.
+                if let (CellType::Cons(head), false) = (storage.val_of(tail),
+                                                        shared.contains(&tail)) {
+                    write!(out, " ")?;
+                    tasks.push(PrintTask::Rest(cdr!(tail, storage)));
+                    tasks.push(PrintTask::Exp(head));
+                } else {
+                    write!(out, " . ")?;
+                    tasks.push(PrintTask::Text(")"));
+                    tasks.push(PrintTask::Exp(tail));
+                }
             }
-            CellType::Cons(_) => print_list(idx, storage, env),
-            _ => {}
         }
     }
+    Ok(())
 }
 
-pub fn print_list(idx: CellIndex, storage: &CellStorage, env: &Env) {
-    print!("(");
-    let mut exp = idx;
+/// Lists every cell in use with its raw contents and tail, one per line.
+pub fn dump_storage(storage: &CellStorage, env: &Env) -> String {
+    let mut out = String::new();
+    for idx in 0..storage.capacity() {
+        let cell = storage.get(idx);
+        let val = match cell.val {
+            CellType::Free => continue,
+            CellType::Symbol(sym) => format!("Symbol({})", env.get_sym(sym)),
+            val => format!("{:?}", val),
+        };
+        out.push_str(&format!("{:>4}  {:<20} {}\n", idx, val, cell.tail));
+    }
+    out
+}
 
-    print_exp(car!(exp, storage), storage, env);
-    exp = cdr!(exp, storage);
+/// Renders `idx` on one line if it fits in `width` columns, and otherwise
+/// breaks lists so that each element starts a line of its own, indented to
+/// line up under the first.
+pub fn pretty_exp(idx: CellIndex, storage: &CellStorage, env: &Env, width: usize) -> String {
+    let mut out = String::new();
+    write_pretty(&mut out, idx, 0, storage, env, width);
+    out
+}
 
-    while let CellType::Cons(head) = storage.val_of(exp) {
-        print!(" ");
-        print_exp(head, storage, env);
+fn write_pretty(out: &mut String,
+                idx: CellIndex,
+                indent: usize,
+                storage: &CellStorage,
+                env: &Env,
+                width: usize) {
+    let flat = exp_to_string(idx, storage, env);
+    if !is_cons(idx, storage) || indent + flat.len() <= width {
+        out.push_str(&flat);
+        return;
+    }
+    let inner = indent + 1;
+    out.push('(');
+    write_pretty(out, car!(idx, storage), inner, storage, env, width);
+    let mut exp = cdr!(idx, storage);
+    while is_cons(exp, storage) {
+        out.push('\n');
+        out.push_str(&" ".repeat(inner));
+        write_pretty(out, car!(exp, storage), inner, storage, env, width);
         exp = cdr!(exp, storage);
     }
+    if exp != NIL_INDEX {
+        out.push('\n');
+        out.push_str(&" ".repeat(inner));
+        out.push_str(". ");
+        write_pretty(out, exp, inner + 2, storage, env, width);
+    }
+    out.push(')');
+}
+
+/// Renders the boxes-and-arrows diagram of the cons cells in `idx`, with
+/// each car hanging below its box.
+pub fn draw_exp(idx: CellIndex, storage: &CellStorage, env: &Env) -> String {
+    draw_block(idx, storage, env).join("\n")
+}
+
+fn draw_block(idx: CellIndex, storage: &CellStorage, env: &Env) -> Vec<String> {
+    if !is_cons(idx, storage) {
+        return vec![exp_to_string(idx, storage, env)];
+    }
 
+    let mut canvas = Vec::new();
+    let mut offset = 0;
+    let mut exp = idx;
+    while let CellType::Cons(head) = storage.val_of(exp) {
+        exp = cdr!(exp, storage);
+        draw_at(&mut canvas, 0, offset, if exp == NIL_INDEX { "[*|/]" } else { "[*|*]" });
+        draw_at(&mut canvas, 1, offset + 1, "|");
+
+        let block = draw_block(head, storage, env);
+        let width = block.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        for (row, line) in block.iter().enumerate() {
+            draw_at(&mut canvas, row + 2, offset + 1, line);
+        }
+
+        if exp != NIL_INDEX {
+            let next = offset + cmp::max(5, width + 1) + 4;
+            let arrow = "-".repeat(next - offset - 6) + ">";
+            draw_at(&mut canvas, 0, offset + 5, &arrow);
+            offset = next;
+        }
+    }
     if exp != NIL_INDEX {
-        print!(" . ");
-        print_exp(exp, storage, env);
+        draw_at(&mut canvas, 0, offset, &exp_to_string(exp, storage, env));
+    }
+
+    canvas.into_iter()
+        .map(|line| line.into_iter().collect::<String>().trim_end().to_string())
+        .collect()
+}
+
+fn draw_at(canvas: &mut Vec<Vec<char>>, row: usize, col: usize, text: &str) {
+    while canvas.len() <= row {
+        canvas.push(Vec::new());
+    }
+    let line = &mut canvas[row];
+    for (i, ch) in text.chars().enumerate() {
+        while line.len() <= col + i {
+            line.push(' ');
+        }
+        line[col + i] = ch;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use env::HashKey;
+    use parser::Parser;
+
+    #[test]
+    fn print_matches_list_syntax() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"(1 (2 ()) (a . b) . 3)".to_vec();
+        let (idx, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(exp_to_string(idx, &cells, &env), "(1 (2 ()) (a . b) . 3)");
+    }
+
+    #[test]
+    fn print_numbers_in_radix() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"(255 0 10)".to_vec();
+        let (idx, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(exp_to_string(idx, &cells, &env), "(255 0 10)");
+        env.radix = 16;
+        assert_eq!(exp_to_string(idx, &cells, &env), "(0xff 0x0 0xa)");
+        env.radix = 2;
+        assert_eq!(exp_to_string(idx, &cells, &env), "(0b11111111 0b0 0b1010)");
+        env.radix = 8;
+        let negative = cells.alloc_cell(CellType::Number(-8));
+        assert_eq!(exp_to_string(negative, &cells, &env), "-0o10");
+    }
+
+    #[test]
+    fn print_circle_labels_shared_structure() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        // ((1 . t) (4 . t)) with t = (2 3).
+        let input = b"(2 3)".to_vec();
+        let (tail, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        let one = cells.alloc_cell(CellType::Number(1));
+        let first = cells.alloc_cell(CellType::Cons(one));
+        cells.set_tail(first, tail);
+        let four = cells.alloc_cell(CellType::Number(4));
+        let second = cells.alloc_cell(CellType::Cons(four));
+        cells.set_tail(second, tail);
+        let rest = cells.alloc_cell(CellType::Cons(second));
+        let idx = cells.alloc_cell(CellType::Cons(first));
+        cells.set_tail(idx, rest);
+
+        assert_eq!(exp_to_string(idx, &cells, &env), "((1 2 3) (4 2 3))");
+        env.print_circle = true;
+        assert_eq!(exp_to_string(idx, &cells, &env), "((1 . #0=(2 3)) (4 . #0#))");
+
+        let cycle = cells.alloc_cell(CellType::Cons(one));
+        cells.set_tail(cycle, cycle);
+        assert_eq!(exp_to_string(cycle, &cells, &env), "#0=(1 . #0#)");
+    }
+
+    #[test]
+    fn print_deeply_nested_list() {
+        const DEPTH: usize = 100_000;
+        let mut buf = vec![Cell::empty(); DEPTH + 2];
+        let mut cells = init_storage(&mut buf);
+        let env = Env::new();
+
+        let mut idx = cells.alloc_cell(CellType::Number(1));
+        for _ in 0..DEPTH {
+            idx = cells.alloc_cell(CellType::Cons(idx));
+        }
+        let expected = format!("{}1{}", "(".repeat(DEPTH), ")".repeat(DEPTH));
+        assert_eq!(exp_to_string(idx, &cells, &env), expected);
+    }
+
+    #[test]
+    fn small_numbers_are_interned() {
+        let mut buf = [Cell::empty(); 16];
+        let mut cells = init_storage(&mut buf);
+        let free = cells.free_count();
+
+        let seven = cells.alloc_cell(CellType::Number(7));
+        assert_eq!(cells.alloc_cell(CellType::Number(7)), seven);
+        assert!(cells.is_interned(seven));
+        let low = cells.alloc_cell(CellType::Number(SMALL_INT_MIN));
+        let high = cells.alloc_cell(CellType::Number(SMALL_INT_END - 1));
+        cells.free_cell(seven, &mut Env::new());
+        assert_eq!(cells.free_count(), free);
+        for &(idx, n) in [(seven, 7), (low, SMALL_INT_MIN), (high, SMALL_INT_END - 1)].iter() {
+            match cells.val_of(idx) {
+                CellType::Number(m) => assert_eq!(m, n),
+                other => panic!("expected {}, got {:?}", n, other),
+            }
+        }
+
+        let big = cells.alloc_cell(CellType::Number(SMALL_INT_END));
+        assert!(!cells.is_interned(big));
+        assert_eq!(cells.free_count(), free - 1);
+    }
+
+    #[test]
+    fn freeing_vectors_and_tables_retires_them() {
+        let mut buf = [Cell::empty(); 16];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let free = cells.free_count();
+
+        let item = cells.alloc_cell(CellType::Number(1000));
+        let vec = env.add_vector(vec![item]);
+        let idx = cells.alloc_cell(CellType::Vector(vec));
+        cells.free_cell(idx, &mut env);
+        assert_eq!(cells.free_count(), free);
+        assert_eq!(env.add_vector(Vec::new()), vec);
+
+        let table = env.add_hash_table();
+        env.get_hash_table_mut(table).insert(HashKey::Number(1), item);
+        let idx = cells.alloc_cell(CellType::HashTable(table));
+        cells.free_cell(idx, &mut env);
+        assert_eq!(cells.free_count(), free);
+        assert_eq!(env.add_hash_table(), table);
+        assert!(env.get_hash_table(table).is_empty());
+    }
+
+    #[test]
+    fn dump_lists_parsed_cells() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        // Numbers this big aren't interned, so they get cells of their own.
+        let input = b"(add 1000 2000)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        let dump = dump_storage(&cells, &env);
+        let rows: Vec<Vec<&str>> =
+            dump.lines().map(|line| line.split_whitespace().collect()).collect();
+
+        assert_eq!(rows.len(), 6);
+        assert!(rows.contains(&vec!["1", "Symbol(add)", "0"]));
+        assert!(rows.contains(&vec![&exp.to_string()[..], "Cons(1)", "5"]));
+    }
+
+    #[test]
+    fn pretty_print_breaks_long_lists() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"(fold-right add 0 (range (mul 10 10)) (1 2 . 3))".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(pretty_exp(exp, &cells, &env, 80),
+                   "(fold-right add 0 (range (mul 10 10)) (1 2 . 3))");
+        assert_eq!(pretty_exp(exp, &cells, &env, 20),
+                   "(fold-right\n add\n 0\n (range (mul 10 10))\n (1 2 . 3))");
+        assert_eq!(pretty_exp(exp, &cells, &env, 8),
+                   "(fold-right\n add\n 0\n (range\n  (mul\n   10\n   10))\n (1\n  2\n  . 3))");
+    }
+
+    #[test]
+    fn draw_two_element_list() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"(1 2)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(draw_exp(exp, &cells, &env),
+                   "[*|*]--->[*|/]\n |        |\n 1        2");
+    }
+
+    #[test]
+    fn draw_nested_and_dotted() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"((1 2) . 3)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(draw_exp(exp, &cells, &env),
+                   ["[*|*]------------->3",
+                    " |",
+                    " [*|*]--->[*|/]",
+                    "  |        |",
+                    "  1        2"]
+                       .join("\n"));
     }
-    print!(")");
 }
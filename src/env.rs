@@ -1,11 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::time::Instant;
+
+use cell::{CellIndex, NIL_INDEX};
+use parser::SpanTable;
+
 pub type SymbolIndex = usize;
+pub type VectorIndex = usize;
+pub type HashTableIndex = usize;
+pub type PromiseIndex = usize;
+
+/// A delayed expression and, once forced, its value.
+#[derive(Clone, Debug)]
+pub struct Promise {
+    pub expr: CellIndex,
+    pub value: Option<CellIndex>,
+}
+
+/// A small seedable pseudo-random number generator (SplitMix64). Good
+/// enough for games and demos, not for anything needing real randomness.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `[0, n)`. The bias from reducing modulo `n` is too small
+    /// to matter for `n` that fit in an `i32`.
+    pub fn below(&mut self, n: u32) -> u32 {
+        (self.next_u64() % n as u64) as u32
+    }
+}
+
+/// A macro made by `defmacro`: its uses are replaced by `template` with
+/// each parameter standing for the corresponding unevaluated argument.
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub params: Vec<SymbolIndex>,
+    pub template: CellIndex,
+}
+
+/// The atoms that can be used as hash table keys.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Number(i32),
+    Symbol(SymbolIndex),
+}
+
+/// Which values conditions treat as false. There is no boolean false value
+/// yet, so there is no policy where only it is false.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Truthiness {
+    /// Only the empty list is false.
+    NilOnly,
+    /// The empty list and the number 0 are false.
+    NilAndZero,
+}
+
+/// What evaluated code may reach outside the interpreter. Builtins that
+/// need a capability raise `EvalError::PermissionDenied` when it's off, so
+/// a host running untrusted code can switch them off.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    /// `exit`.
+    pub exit: bool,
+    /// `random` and `time-ms`, whose results differ from run to run.
+    pub random_and_clock: bool,
+}
+impl Capabilities {
+    pub fn all() -> Capabilities {
+        Capabilities {
+            exit: true,
+            random_and_clock: true,
+        }
+    }
+
+    pub fn none() -> Capabilities {
+        Capabilities {
+            exit: false,
+            random_and_clock: false,
+        }
+    }
+}
 
 pub struct Env {
     symbols: Vec<String>,
+    /// The elements of each vector, indexed by `CellType::Vector`.
+    vectors: Vec<Vec<CellIndex>>,
+    /// Indices of vectors whose cells have been freed, for reuse.
+    free_vectors: Vec<VectorIndex>,
+    /// The entries of each hash table, indexed by `CellType::HashTable`.
+    hash_tables: Vec<HashMap<HashKey, CellIndex>>,
+    /// Indices of hash tables whose cells have been freed, for reuse.
+    free_hash_tables: Vec<HashTableIndex>,
+    macros: HashMap<SymbolIndex, Macro>,
+    /// The bodies registered by `define-test`, in order.
+    tests: Vec<(SymbolIndex, CellIndex)>,
+    /// The delayed expressions, indexed by `CellType::Promise`.
+    promises: Vec<Promise>,
+    /// Indices of promises whose cells have been freed, for reuse.
+    free_promises: Vec<PromiseIndex>,
+    started: Instant,
+    /// When set, evaluating a symbol that isn't a builtin is an error rather
+    /// than yielding the symbol itself.
+    pub strict: bool,
+    /// When set, constant arithmetic is folded before evaluation.
+    pub fold_constants: bool,
+    /// The values `fold_constants` found for constant forms. The forms are
+    /// left as they were written so that errors still print them that way.
+    folded: HashMap<CellIndex, i32>,
+    /// When set, evaluation errors carry the printed form of the expression
+    /// that raised them.
+    pub error_context: bool,
+    /// When present, the parser records where each cell came from here so
+    /// that errors can point at the offending line and column.
+    pub spans: Option<SpanTable>,
+    pub truthiness: Truthiness,
+    /// When set, `exit` ends the process. Otherwise it raises
+    /// `EvalError::Exit` so that an embedding host can decide what to do.
+    pub exit_process: bool,
+    /// When present, how many calls to `eval` an evaluation may make before
+    /// it is stopped with `EvalError::StepLimit`.
+    pub step_limit: Option<usize>,
+    /// The calls to `eval` made so far, counted against `step_limit`. Reset
+    /// it before each top-level evaluation.
+    pub steps: usize,
+    /// The base numbers are printed in: 2, 8, 10 or 16. Anything but 10
+    /// gets a `0b`, `0o` or `0x` prefix. The reader only takes decimal.
+    pub radix: u32,
+    /// When set, conses reached more than once while printing get a
+    /// `#n=` label on first sight and print as `#n#` after that, so shared
+    /// and cyclic structure shows up and cycles still print.
+    pub print_circle: bool,
+    /// Where `random` gets its numbers. It starts from seed 0 so that runs
+    /// are reproducible unless the host seeds it.
+    pub rng: Rng,
+    /// Everything is allowed unless the host restricts it.
+    pub capabilities: Capabilities,
+    /// What builtins like `draw` have printed, waiting for the host to write
+    /// it wherever its output goes.
+    printed: String,
 }
 impl Env {
     pub fn new() -> Env {
-        Env { symbols: Vec::new() }
+        Env {
+            symbols: Vec::new(),
+            vectors: Vec::new(),
+            free_vectors: Vec::new(),
+            hash_tables: Vec::new(),
+            free_hash_tables: Vec::new(),
+            macros: HashMap::new(),
+            tests: Vec::new(),
+            promises: Vec::new(),
+            free_promises: Vec::new(),
+            started: Instant::now(),
+            strict: false,
+            fold_constants: false,
+            folded: HashMap::new(),
+            error_context: false,
+            spans: None,
+            truthiness: Truthiness::NilOnly,
+            exit_process: false,
+            step_limit: None,
+            steps: 0,
+            radix: 10,
+            print_circle: false,
+            rng: Rng::new(0),
+            capabilities: Capabilities::all(),
+            printed: String::new(),
+        }
     }
 
     pub fn add_sym(&mut self, name: String) -> SymbolIndex {
@@ -21,6 +195,191 @@ impl Env {
     pub fn get_sym(&self, sym: SymbolIndex) -> &String {
         &self.symbols[sym]
     }
+
+    /// How many symbols have been interned; their indices run from 0 up.
+    pub fn symbol_count(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Milliseconds since the environment was created.
+    pub fn elapsed_ms(&self) -> u128 {
+        self.started.elapsed().as_millis()
+    }
+
+    pub fn add_vector(&mut self, items: Vec<CellIndex>) -> VectorIndex {
+        match self.free_vectors.pop() {
+            Some(vec) => {
+                self.vectors[vec] = items;
+                vec
+            }
+            None => {
+                self.vectors.push(items);
+                self.vectors.len() - 1
+            }
+        }
+    }
+
+    /// Retires a vector whose cell has been freed, handing back its elements
+    /// and letting `add_vector` reuse the index.
+    pub fn remove_vector(&mut self, vec: VectorIndex) -> Vec<CellIndex> {
+        self.free_vectors.push(vec);
+        mem::take(&mut self.vectors[vec])
+    }
+
+    pub fn get_vector(&self, vec: VectorIndex) -> &[CellIndex] {
+        &self.vectors[vec]
+    }
+
+    pub fn get_vector_mut(&mut self, vec: VectorIndex) -> &mut [CellIndex] {
+        &mut self.vectors[vec]
+    }
+
+    pub fn define_macro(&mut self, name: SymbolIndex, mac: Macro) {
+        self.macros.insert(name, mac);
+    }
+
+    pub fn get_macro(&self, name: SymbolIndex) -> Option<&Macro> {
+        self.macros.get(&name)
+    }
+
+    /// Prints `text` and a newline to the host's output.
+    pub fn print_line(&mut self, text: &str) {
+        self.printed.push_str(text);
+        self.printed.push('\n');
+    }
+
+    /// Hands over everything printed since the last call.
+    pub fn take_printed(&mut self) -> String {
+        mem::take(&mut self.printed)
+    }
+
+    pub fn set_folded(&mut self, form: CellIndex, val: i32) {
+        self.folded.insert(form, val);
+    }
+
+    pub fn get_folded(&self, form: CellIndex) -> Option<i32> {
+        self.folded.get(&form).cloned()
+    }
+
+    /// Forgets `form`'s folded value, for when the cell is freed.
+    pub fn forget_folded(&mut self, form: CellIndex) {
+        self.folded.remove(&form);
+    }
+
+    /// Registers a test, replacing any earlier one with the same name.
+    pub fn define_test(&mut self, name: SymbolIndex, body: CellIndex) {
+        match self.tests.iter_mut().find(|test| test.0 == name) {
+            Some(test) => test.1 = body,
+            None => self.tests.push((name, body)),
+        }
+    }
+
+    pub fn tests(&self) -> &[(SymbolIndex, CellIndex)] {
+        &self.tests
+    }
+
+    /// The cells kept beyond the evaluation that made them: macro templates
+    /// and test bodies.
+    pub fn persistent_cells(&self) -> Vec<CellIndex> {
+        self.macros
+            .values()
+            .map(|mac| mac.template)
+            .chain(self.tests.iter().map(|test| test.1))
+            .collect()
+    }
+
+    /// The cells held by the vectors, hash tables and promises in use, except
+    /// the ones given.
+    pub fn held_cells(&self,
+                      vectors: &HashSet<VectorIndex>,
+                      tables: &HashSet<HashTableIndex>,
+                      promises: &HashSet<PromiseIndex>)
+                      -> Vec<CellIndex> {
+        let mut held = Vec::new();
+        for (vec, items) in self.vectors.iter().enumerate() {
+            if !vectors.contains(&vec) {
+                held.extend_from_slice(items);
+            }
+        }
+        for (table, entries) in self.hash_tables.iter().enumerate() {
+            if !tables.contains(&table) {
+                held.extend(entries.values());
+            }
+        }
+        for (idx, promise) in self.promises.iter().enumerate() {
+            if !promises.contains(&idx) {
+                held.push(promise.expr);
+                held.extend(promise.value);
+            }
+        }
+        held
+    }
+
+    pub fn add_promise(&mut self, expr: CellIndex) -> PromiseIndex {
+        let promise = Promise { expr, value: None };
+        match self.free_promises.pop() {
+            Some(idx) => {
+                self.promises[idx] = promise;
+                idx
+            }
+            None => {
+                self.promises.push(promise);
+                self.promises.len() - 1
+            }
+        }
+    }
+
+    /// Retires a promise whose cell has been freed, handing back its
+    /// expression and value and letting `add_promise` reuse the index.
+    pub fn remove_promise(&mut self, promise: PromiseIndex) -> Promise {
+        self.free_promises.push(promise);
+        mem::replace(&mut self.promises[promise],
+                     Promise {
+                         expr: NIL_INDEX,
+                         value: None,
+                     })
+    }
+
+    pub fn get_promise(&self, promise: PromiseIndex) -> &Promise {
+        &self.promises[promise]
+    }
+
+    pub fn get_promise_mut(&mut self, promise: PromiseIndex) -> &mut Promise {
+        &mut self.promises[promise]
+    }
+
+    pub fn add_hash_table(&mut self) -> HashTableIndex {
+        match self.free_hash_tables.pop() {
+            Some(table) => table,
+            None => {
+                self.hash_tables.push(HashMap::new());
+                self.hash_tables.len() - 1
+            }
+        }
+    }
+
+    /// Retires a hash table whose cell has been freed, handing back its
+    /// values and letting `add_hash_table` reuse the index.
+    pub fn remove_hash_table(&mut self, table: HashTableIndex) -> Vec<CellIndex> {
+        self.free_hash_tables.push(table);
+        self.hash_tables[table].drain().map(|(_, val)| val).collect()
+    }
+
+    pub fn get_hash_table(&self, table: HashTableIndex) -> &HashMap<HashKey, CellIndex> {
+        &self.hash_tables[table]
+    }
+
+    pub fn get_hash_table_mut(&mut self,
+                              table: HashTableIndex)
+                              -> &mut HashMap<HashKey, CellIndex> {
+        &mut self.hash_tables[table]
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::new()
+    }
 }
 
 pub struct DefaultNS {
@@ -33,6 +392,88 @@ pub struct DefaultNS {
     pub hd: SymbolIndex,
     pub tl: SymbolIndex,
     pub quote: SymbolIndex,
+    pub count_cells: SymbolIndex,
+    pub make_list: SymbolIndex,
+    pub map2: SymbolIndex,
+    pub copy: SymbolIndex,
+    pub deep_copy: SymbolIndex,
+    pub draw: SymbolIndex,
+    pub t: SymbolIndex,
+    pub positive: SymbolIndex,
+    pub negative: SymbolIndex,
+    pub zero: SymbolIndex,
+    pub even: SymbolIndex,
+    pub odd: SymbolIndex,
+    pub gcd: SymbolIndex,
+    pub lcm: SymbolIndex,
+    pub lt: SymbolIndex,
+    pub sort: SymbolIndex,
+    pub take: SymbolIndex,
+    pub drop: SymbolIndex,
+    pub range: SymbolIndex,
+    pub fold_right: SymbolIndex,
+    pub error: SymbolIndex,
+    pub catch: SymbolIndex,
+    pub begin: SymbolIndex,
+    pub progn: SymbolIndex,
+    pub reduce: SymbolIndex,
+    pub last: SymbolIndex,
+    pub last_pair: SymbolIndex,
+    pub flatten: SymbolIndex,
+    pub bit_and: SymbolIndex,
+    pub bit_or: SymbolIndex,
+    pub bit_xor: SymbolIndex,
+    pub bit_not: SymbolIndex,
+    pub shl: SymbolIndex,
+    pub shr: SymbolIndex,
+    pub memq: SymbolIndex,
+    pub assq: SymbolIndex,
+    pub exit: SymbolIndex,
+    pub vector: SymbolIndex,
+    pub list_to_vector: SymbolIndex,
+    pub vector_ref: SymbolIndex,
+    pub vector_length: SymbolIndex,
+    pub vector_set: SymbolIndex,
+    pub make_hash: SymbolIndex,
+    pub hash_set: SymbolIndex,
+    pub hash_ref: SymbolIndex,
+    pub hash_remove: SymbolIndex,
+    pub help: SymbolIndex,
+    pub modulo: SymbolIndex,
+    pub remainder: SymbolIndex,
+    pub defmacro: SymbolIndex,
+    pub symbols: SymbolIndex,
+    pub bound: SymbolIndex,
+    pub car_safe: SymbolIndex,
+    pub cdr_safe: SymbolIndex,
+    pub macroexpand: SymbolIndex,
+    pub delay: SymbolIndex,
+    pub force: SymbolIndex,
+    pub max_depth: SymbolIndex,
+    pub pp: SymbolIndex,
+    pub list_tabulate: SymbolIndex,
+    pub quotient: SymbolIndex,
+    pub random: SymbolIndex,
+    pub set_seed: SymbolIndex,
+    pub time_ms: SymbolIndex,
+    pub assert: SymbolIndex,
+    pub define_test: SymbolIndex,
+    pub run_tests: SymbolIndex,
+    pub cells_used: SymbolIndex,
+    pub cells_free: SymbolIndex,
+    pub deep_reverse: SymbolIndex,
+    pub take_while: SymbolIndex,
+    pub drop_while: SymbolIndex,
+    pub partition: SymbolIndex,
+    pub count: SymbolIndex,
+    pub any: SymbolIndex,
+    pub every: SymbolIndex,
+    pub list_copy: SymbolIndex,
+    pub remove: SymbolIndex,
+    pub remq: SymbolIndex,
+    pub assoc_set: SymbolIndex,
+    pub unfold: SymbolIndex,
+    pub compile: SymbolIndex,
 }
 impl DefaultNS {
     pub fn new(env: &mut Env) -> Self {
@@ -46,6 +487,279 @@ impl DefaultNS {
             hd: env.add_sym("hd".to_string()),
             tl: env.add_sym("tl".to_string()),
             quote: env.add_sym("'".to_string()),
+            count_cells: env.add_sym("count-cells".to_string()),
+            make_list: env.add_sym("make-list".to_string()),
+            map2: env.add_sym("map2".to_string()),
+            copy: env.add_sym("copy".to_string()),
+            deep_copy: env.add_sym("deep-copy".to_string()),
+            draw: env.add_sym("draw".to_string()),
+            t: env.add_sym("t".to_string()),
+            positive: env.add_sym("positive?".to_string()),
+            negative: env.add_sym("negative?".to_string()),
+            zero: env.add_sym("zero?".to_string()),
+            even: env.add_sym("even?".to_string()),
+            odd: env.add_sym("odd?".to_string()),
+            gcd: env.add_sym("gcd".to_string()),
+            lcm: env.add_sym("lcm".to_string()),
+            lt: env.add_sym("lt".to_string()),
+            sort: env.add_sym("sort".to_string()),
+            take: env.add_sym("take".to_string()),
+            drop: env.add_sym("drop".to_string()),
+            range: env.add_sym("range".to_string()),
+            fold_right: env.add_sym("fold-right".to_string()),
+            error: env.add_sym("error".to_string()),
+            catch: env.add_sym("catch".to_string()),
+            begin: env.add_sym("begin".to_string()),
+            progn: env.add_sym("progn".to_string()),
+            reduce: env.add_sym("reduce".to_string()),
+            last: env.add_sym("last".to_string()),
+            last_pair: env.add_sym("last-pair".to_string()),
+            flatten: env.add_sym("flatten".to_string()),
+            bit_and: env.add_sym("bit-and".to_string()),
+            bit_or: env.add_sym("bit-or".to_string()),
+            bit_xor: env.add_sym("bit-xor".to_string()),
+            bit_not: env.add_sym("bit-not".to_string()),
+            shl: env.add_sym("shl".to_string()),
+            shr: env.add_sym("shr".to_string()),
+            memq: env.add_sym("memq".to_string()),
+            assq: env.add_sym("assq".to_string()),
+            exit: env.add_sym("exit".to_string()),
+            vector: env.add_sym("vector".to_string()),
+            list_to_vector: env.add_sym("list->vector".to_string()),
+            vector_ref: env.add_sym("vector-ref".to_string()),
+            vector_length: env.add_sym("vector-length".to_string()),
+            vector_set: env.add_sym("vector-set!".to_string()),
+            make_hash: env.add_sym("make-hash".to_string()),
+            hash_set: env.add_sym("hash-set!".to_string()),
+            hash_ref: env.add_sym("hash-ref".to_string()),
+            hash_remove: env.add_sym("hash-remove!".to_string()),
+            help: env.add_sym("help".to_string()),
+            modulo: env.add_sym("modulo".to_string()),
+            remainder: env.add_sym("remainder".to_string()),
+            defmacro: env.add_sym("defmacro".to_string()),
+            symbols: env.add_sym("symbols".to_string()),
+            bound: env.add_sym("bound?".to_string()),
+            car_safe: env.add_sym("car-safe".to_string()),
+            cdr_safe: env.add_sym("cdr-safe".to_string()),
+            macroexpand: env.add_sym("macroexpand".to_string()),
+            delay: env.add_sym("delay".to_string()),
+            force: env.add_sym("force".to_string()),
+            max_depth: env.add_sym("max-depth".to_string()),
+            pp: env.add_sym("pp".to_string()),
+            list_tabulate: env.add_sym("list-tabulate".to_string()),
+            quotient: env.add_sym("quotient".to_string()),
+            random: env.add_sym("random".to_string()),
+            set_seed: env.add_sym("set-seed!".to_string()),
+            time_ms: env.add_sym("time-ms".to_string()),
+            assert: env.add_sym("assert".to_string()),
+            define_test: env.add_sym("define-test".to_string()),
+            run_tests: env.add_sym("run-tests".to_string()),
+            cells_used: env.add_sym("cells-used".to_string()),
+            cells_free: env.add_sym("cells-free".to_string()),
+            deep_reverse: env.add_sym("deep-reverse".to_string()),
+            take_while: env.add_sym("take-while".to_string()),
+            drop_while: env.add_sym("drop-while".to_string()),
+            partition: env.add_sym("partition".to_string()),
+            count: env.add_sym("count".to_string()),
+            any: env.add_sym("any".to_string()),
+            every: env.add_sym("every".to_string()),
+            list_copy: env.add_sym("list-copy".to_string()),
+            remove: env.add_sym("remove".to_string()),
+            remq: env.add_sym("remq".to_string()),
+            assoc_set: env.add_sym("assoc-set".to_string()),
+            unfold: env.add_sym("unfold".to_string()),
+            compile: env.add_sym("compile".to_string()),
         }
     }
+
+    /// A short description of the builtin `sym`, starting with its usage.
+    pub fn help(&self, sym: SymbolIndex) -> Option<&'static str> {
+        let table = [(self.add, "(add a b): arithmetic, the sum of two arguments"),
+                     (self.sub, "(sub a b): arithmetic, the difference of two arguments"),
+                     (self.mul, "(mul a b): arithmetic, the product of two arguments"),
+                     (self.div, "(div a b): arithmetic, the quotient of two arguments"),
+                     (self.modu, "(mod a b): arithmetic, the remainder of two arguments"),
+                     (self.quotient, "(quotient a b): arithmetic, a / b rounded toward 0"),
+                     (self.modulo, "(modulo a b): arithmetic, a modulo b with the sign of b"),
+                     (self.remainder, "(remainder a b): arithmetic, the same as mod"),
+                     (self.cons, "(cons a b): a new pair with head a and tail b"),
+                     (self.hd, "(hd pair): the head of a pair"),
+                     (self.tl, "(tl pair): the tail of a pair"),
+                     (self.car_safe, "(car-safe x): the head of x if it's a pair, else ()"),
+                     (self.cdr_safe, "(cdr-safe x): the tail of x if it's a pair, else ()"),
+                     (self.quote, "(' x): x, unevaluated"),
+                     (self.count_cells, "(count-cells x): the number of distinct cons cells in x"),
+                     (self.make_list, "(make-list n x): a list of n copies of x"),
+                     (self.map2, "(map2 f xs ys): f applied to the pairs of elements of two lists"),
+                     (self.copy, "(copy x): a copy of every cons cell in x"),
+                     (self.deep_copy, "(deep-copy x): a copy of every cons cell in x"),
+                     (self.list_copy, "(list-copy xs): a copy of the spine of xs only"),
+                     (self.draw, "(draw x): prints the box diagram of x and returns it"),
+                     (self.pp, "(pp x): pretty-prints x over several lines and returns it"),
+                     (self.t, "t: the true value"),
+                     (self.positive, "(positive? n): whether one number is above zero"),
+                     (self.negative, "(negative? n): whether one number is below zero"),
+                     (self.zero, "(zero? n): whether one number is zero"),
+                     (self.even, "(even? n): whether one number is even"),
+                     (self.odd, "(odd? n): whether one number is odd"),
+                     (self.gcd, "(gcd n ...): the greatest common divisor of its arguments"),
+                     (self.lcm, "(lcm n ...): the least common multiple of its arguments"),
+                     (self.lt, "(lt a b): whether the first of two numbers is the smaller"),
+                     (self.sort, "(sort xs less): xs stably sorted by the function less"),
+                     (self.take, "(take n xs): a copy of the first n elements of xs"),
+                     (self.drop, "(drop n xs): xs without its first n elements"),
+                     (self.take_while, "(take-while p xs): the leading run of xs satisfying p"),
+                     (self.drop_while, "(drop-while p xs): xs after its leading run satisfying p"),
+                     (self.partition, "(partition p xs): the list ((xs satisfying p) (the rest))"),
+                     (self.count, "(count p xs): how many elements of xs satisfy p"),
+                     (self.any, "(any p xs): the first true result of p on xs, else ()"),
+                     (self.every, "(every p xs): t if p holds for every element of xs"),
+                     (self.remove, "(remove x xs): xs without the elements equal to x"),
+                     (self.remq, "(remq x xs): xs without the elements eq to x"),
+                     (self.assoc_set, "(assoc-set al k v): al with the pair for k set to (k . v)"),
+                     (self.unfold, "(unfold stop f seed next): (f seed) ... until (stop seed)"),
+                     (self.compile, "(compile e): the bytecode for the arithmetic e, unevaluated"),
+                     (self.range, "(range [start] end): the numbers from start up to end"),
+                     (self.list_tabulate, "(list-tabulate n f): the list of (f i) for i below n"),
+                     (self.fold_right, "(fold-right f init xs): xs folded with f from the right"),
+                     (self.error, "(error x): raises an error showing x"),
+                     (self.catch, "(catch body f): body, or (f message) if body raises an error"),
+                     (self.begin, "(begin x ...): evaluates each argument, giving the last"),
+                     (self.progn, "(progn x ...): evaluates each argument, giving the last"),
+                     (self.reduce, "(reduce f xs): xs folded with f from the left"),
+                     (self.last, "(last xs): the last element of a list"),
+                     (self.last_pair, "(last-pair xs): the last pair of a list"),
+                     (self.deep_reverse, "(deep-reverse x): x reversed at every level"),
+                     (self.flatten, "(flatten x): the atoms of a nested list, in order"),
+                     (self.bit_and, "(bit-and a b): the bitwise and of two numbers"),
+                     (self.bit_or, "(bit-or a b): the bitwise or of two numbers"),
+                     (self.bit_xor, "(bit-xor a b): the bitwise exclusive or of two numbers"),
+                     (self.bit_not, "(bit-not a): the bitwise complement of one number"),
+                     (self.shl, "(shl a n): a shifted left by 0 to 31 bits"),
+                     (self.shr, "(shr a n): a shifted right by 0 to 31 bits"),
+                     (self.memq, "(memq x xs): the rest of xs from the first element eq to x"),
+                     (self.assq, "(assq k pairs): the first pair whose head is eq to k"),
+                     (self.exit, "(exit [code]): stops with the status code, 0 by default"),
+                     (self.vector, "(vector x ...): a vector of any number of arguments"),
+                     (self.list_to_vector, "(list->vector xs): a vector of the elements of xs"),
+                     (self.vector_ref, "(vector-ref v i): element i of a vector"),
+                     (self.vector_length, "(vector-length v): the number of elements of a vector"),
+                     (self.vector_set, "(vector-set! v i x): replaces element i of a vector"),
+                     (self.make_hash, "(make-hash): a new empty hash table"),
+                     (self.hash_set, "(hash-set! h k v): stores v under the key k"),
+                     (self.hash_ref, "(hash-ref h k default): the value under k, or default"),
+                     (self.hash_remove, "(hash-remove! h k): removes the key k"),
+                     (self.defmacro, "(defmacro name (param ...) template): defines a macro"),
+                     (self.symbols, "(symbols): a list of every interned symbol"),
+                     (self.bound, "(bound? name): whether name is a builtin or a macro"),
+                     (self.macroexpand, "(macroexpand form): form with its macro expanded once"),
+                     (self.delay, "(delay x): a promise to evaluate x when forced"),
+                     (self.force, "(force p): the value of a promise, evaluated only once"),
+                     (self.max_depth, "(max-depth x): how deeply x nests, 0 for an atom"),
+                     (self.random, "(random n): a pseudo-random number from 0 up to below n"),
+                     (self.set_seed, "(set-seed! n): restarts random from the seed n"),
+                     (self.time_ms, "(time-ms): milliseconds since the interpreter started"),
+                     (self.assert, "(assert x): the value of x, or an error if it's false"),
+                     (self.define_test, "(define-test name body): registers a test"),
+                     (self.run_tests, "(run-tests): (passes failures (failed test ...))"),
+                     (self.cells_used, "(cells-used): the number of cells in use"),
+                     (self.cells_free, "(cells-free): how many cells are free"),
+                     (self.help, "(help op): prints a description of the builtin op")];
+        table.iter().find(|entry| entry.0 == sym).map(|entry| entry.1)
+    }
+
+    pub fn is_builtin(&self, sym: SymbolIndex) -> bool {
+        [self.add,
+         self.sub,
+         self.mul,
+         self.div,
+         self.modu,
+         self.cons,
+         self.hd,
+         self.tl,
+         self.quote,
+         self.count_cells,
+         self.make_list,
+         self.map2,
+         self.copy,
+         self.deep_copy,
+         self.draw,
+         self.t,
+         self.positive,
+         self.negative,
+         self.zero,
+         self.even,
+         self.odd,
+         self.gcd,
+         self.lcm,
+         self.lt,
+         self.sort,
+         self.take,
+         self.drop,
+         self.range,
+         self.fold_right,
+         self.error,
+         self.catch,
+         self.begin,
+         self.progn,
+         self.reduce,
+         self.last,
+         self.last_pair,
+         self.flatten,
+         self.bit_and,
+         self.bit_or,
+         self.bit_xor,
+         self.bit_not,
+         self.shl,
+         self.shr,
+         self.memq,
+         self.assq,
+         self.exit,
+         self.vector,
+         self.list_to_vector,
+         self.vector_ref,
+         self.vector_length,
+         self.vector_set,
+         self.make_hash,
+         self.hash_set,
+         self.hash_ref,
+         self.hash_remove,
+         self.help,
+         self.modulo,
+         self.remainder,
+         self.defmacro,
+         self.symbols,
+         self.bound,
+         self.car_safe,
+         self.cdr_safe,
+         self.macroexpand,
+         self.delay,
+         self.force,
+         self.max_depth,
+         self.pp,
+         self.list_tabulate,
+         self.quotient,
+         self.random,
+         self.set_seed,
+         self.time_ms,
+         self.assert,
+         self.define_test,
+         self.run_tests,
+         self.cells_used,
+         self.cells_free,
+         self.deep_reverse,
+         self.take_while,
+         self.drop_while,
+         self.partition,
+         self.count,
+         self.any,
+         self.every,
+         self.list_copy,
+         self.remove,
+         self.remq,
+         self.assoc_set,
+         self.unfold,
+         self.compile]
+            .contains(&sym)
+    }
 }
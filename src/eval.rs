@@ -1,5 +1,19 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::process;
+
 use cell::*;
-use env::{Env, DefaultNS, SymbolIndex};
+use env::{Env, DefaultNS, HashKey, HashTableIndex, Macro, Rng, SymbolIndex, Truthiness,
+          VectorIndex};
+use parser::Span;
+use vm::{self, Op};
+
+/// How deeply nested a structure the recursive list builtins will walk.
+const MAX_DEPTH: usize = 1000;
+
+/// The line width `pp` fits its output to.
+const PP_WIDTH: usize = 72;
 
 #[derive(Debug)]
 pub enum EvalError {
@@ -7,13 +21,156 @@ pub enum EvalError {
     NonUnary,
     NotCons(CellIndex),
     NonBinary,
+    NonTernary,
     NonNumeric,
     UnknownOperator(SymbolIndex),
+    UnboundSymbol(SymbolIndex),
+    NegativeCount(i32),
+    NotPositive(i32),
+    Overflow,
+    DivideByZero,
+    TooDeep,
+    /// Raised by builtins that build large results when storage runs out.
+    OutOfCells,
+    StepLimit,
+    /// A `defmacro` whose name or parameters aren't symbols.
+    BadMacro(CellIndex),
+    ArgCount {
+        expected: usize,
+        got: usize,
+    },
+    BadShift(i32),
+    NotVector(CellIndex),
+    NotSymbol(CellIndex),
+    IndexOutOfRange(i32),
+    NotHashTable(CellIndex),
+    Unhashable(CellIndex),
+    /// An expression outside what the bytecode compiler handles.
+    NotCompilable(CellIndex),
+    /// Raised by a builtin whose capability the host has switched off.
+    PermissionDenied(SymbolIndex),
+    /// Raised by `exit` with its status code when not exiting the process.
+    Exit(i32),
+    /// Raised by `assert`, carrying the printed form of the expression that
+    /// was false.
+    AssertionFailed(String),
+    /// Raised by `error`, carrying the printed form of its argument.
+    UserError(String),
+    /// Wraps an error with the printed form of the expression that raised it.
+    At {
+        expr: String,
+        span: Option<Span>,
+        cause: Box<EvalError>,
+    },
+}
+
+/// Writes a line saying what went wrong, followed by where if the error
+/// has context attached.
+pub fn describe_err<W: Write>(output: &mut W,
+                          err_type: EvalError,
+                          cells: &CellStorage,
+                          env: &Env)
+                          -> io::Result<()> {
+    match err_type {
+        EvalError::IllegalOperator => writeln!(output, "illegal operator!"),
+        EvalError::NonUnary => writeln!(output, "non unary expression!"),
+        EvalError::NotCons(exp) => {
+            writeln!(output,
+                     "{} does not evaluate to a cons pair!",
+                     exp_to_string(exp, cells, env))
+        }
+        EvalError::NonBinary => writeln!(output, "non binary expression!"),
+        EvalError::NonTernary => writeln!(output, "non ternary expression!"),
+        EvalError::NonNumeric => writeln!(output, "non numeric expression!"),
+        EvalError::UnknownOperator(op) => {
+            writeln!(output, "unknown operator '{}'", env.get_sym(op))
+        }
+        EvalError::UnboundSymbol(sym) => writeln!(output, "unbound symbol '{}'", env.get_sym(sym)),
+        EvalError::NotPositive(n) => writeln!(output, "{} is not positive!", n),
+        EvalError::NegativeCount(n) => writeln!(output, "negative count {}!", n),
+        EvalError::Overflow => writeln!(output, "arithmetic overflow!"),
+        EvalError::DivideByZero => writeln!(output, "division by zero!"),
+        EvalError::TooDeep => writeln!(output, "structure nested too deeply!"),
+        EvalError::StepLimit => writeln!(output, "step limit reached!"),
+        EvalError::BadMacro(exp) => {
+            writeln!(output, "malformed macro {}!", exp_to_string(exp, cells, env))
+        }
+        EvalError::ArgCount { expected, got } => {
+            writeln!(output, "expected {} arguments, got {}!", expected, got)
+        }
+        EvalError::BadShift(n) => writeln!(output, "can't shift by {} bits!", n),
+        EvalError::NotVector(exp) => {
+            writeln!(output,
+                     "{} does not evaluate to a vector!",
+                     exp_to_string(exp, cells, env))
+        }
+        EvalError::NotCompilable(exp) => {
+            writeln!(output, "{} can't be compiled!", exp_to_string(exp, cells, env))
+        }
+        EvalError::NotSymbol(exp) => {
+            writeln!(output, "{} is not a symbol!", exp_to_string(exp, cells, env))
+        }
+        EvalError::NotHashTable(exp) => {
+            writeln!(output,
+                     "{} does not evaluate to a hash table!",
+                     exp_to_string(exp, cells, env))
+        }
+        EvalError::Unhashable(exp) => {
+            writeln!(output,
+                     "{} can't be used as a hash key!",
+                     exp_to_string(exp, cells, env))
+        }
+        EvalError::IndexOutOfRange(n) => writeln!(output, "index {} out of range!", n),
+        EvalError::PermissionDenied(sym) => {
+            writeln!(output, "{} is not permitted here!", env.get_sym(sym))
+        }
+        EvalError::OutOfCells => writeln!(output, "out of cell storage!"),
+        EvalError::Exit(code) => writeln!(output, "exit with status {}", code),
+        EvalError::AssertionFailed(expr) => writeln!(output, "assertion failed: {}", expr),
+        EvalError::UserError(msg) => writeln!(output, "{}", msg),
+        EvalError::At { expr, span, cause } => {
+            describe_err(output, *cause, cells, env)?;
+            match span {
+                Some(span) => writeln!(output, "    in {} at {}:{}", expr, span.line, span.col),
+                None => writeln!(output, "    in {}", expr),
+            }
+        }
+    }
+}
+
+/// Attaches `exp` to `err` when error context is enabled, keeping the
+/// innermost expression if one has already been attached.
+fn at(err: EvalError, exp: CellIndex, cells: &CellStorage, env: &Env) -> EvalError {
+    match err {
+        EvalError::At { .. } | EvalError::Exit(_) => err,
+        _ if !env.error_context => err,
+        _ => {
+            EvalError::At {
+                expr: exp_to_string(exp, cells, env),
+                span: env.spans.as_ref().and_then(|spans| spans.get(exp)),
+                cause: Box::new(err),
+            }
+        }
+    }
+}
+
+/// Whether `exp` counts as true in a condition under the environment's
+/// truthiness policy. Every condition should go through this.
+fn is_truthy(exp: CellIndex, cells: &CellStorage, env: &Env) -> bool {
+    match (env.truthiness, cells.val_of(exp)) {
+        _ if exp == NIL_INDEX => false,
+        (Truthiness::NilAndZero, CellType::Number(0)) => false,
+        _ => true,
+    }
 }
 
 fn is_atom(exp: CellIndex, cells: &CellStorage) -> bool {
     match cells.val_of(exp) {
-        CellType::Number(_) | CellType::Symbol(_) => true,
+        CellType::Number(_) |
+        CellType::Symbol(_) |
+        CellType::Vector(_) |
+        CellType::HashTable(_) |
+        CellType::Promise(_) => true,
         _ => exp == NIL_INDEX,
     }
 }
@@ -24,6 +181,78 @@ fn split_binary(exp: CellIndex, cells: &CellStorage) -> (CellIndex, CellIndex) {
     (head, tail)
 }
 
+/// The number of arguments in the call `exp`.
+fn count_args(exp: CellIndex, cells: &CellStorage) -> usize {
+    let mut count = 0;
+    let mut args = cdr!(exp, cells);
+    while is_cons(args, cells) {
+        count += 1;
+        args = cdr!(args, cells);
+    }
+    count
+}
+
+fn split_ternary(exp: CellIndex, cells: &CellStorage) -> (CellIndex, CellIndex, CellIndex) {
+    let (first, second) = split_binary(exp, cells);
+    let third = car!(cdr!(cdr!(cdr!(exp, cells), cells), cells), cells);
+    (first, second, third)
+}
+
+fn cons(head: CellIndex, tail: CellIndex, cells: &mut CellStorage) -> CellIndex {
+    let idx = cells.alloc_cell(CellType::Cons(head));
+    cells.set_tail(idx, tail);
+    idx
+}
+
+/// Like `cons`, but raises `OutOfCells` rather than panicking, for
+/// builtins whose result size is up to the caller.
+fn try_cons(head: CellIndex,
+            tail: CellIndex,
+            cells: &mut CellStorage)
+            -> Result<CellIndex, EvalError> {
+    let idx = cells.try_alloc_cell(CellType::Cons(head)).ok_or(EvalError::OutOfCells)?;
+    cells.set_tail(idx, tail);
+    Ok(idx)
+}
+
+/// Builds a proper list out of `items`.
+fn list_of(items: &[CellIndex], cells: &mut CellStorage) -> CellIndex {
+    items.iter().rev().fold(NIL_INDEX, |tail, &head| cons(head, tail, cells))
+}
+
+/// Calls the function `f` on already evaluated `args` by evaluating a
+/// temporary `(f (' arg) ...)` form. The form's own cells are released
+/// afterwards; `f`, the arguments and the result are left alone. It raises
+/// `OutOfCells` up front unless there is room for the form and a one-cell
+/// result, so that builtins calling `f` over and over stop cleanly.
+fn apply(f: CellIndex,
+         args: &[CellIndex],
+         cells: &mut CellStorage,
+         env: &mut Env,
+         ns: &DefaultNS)
+         -> Result<CellIndex, EvalError> {
+    if !cells.has_free(3 * args.len() + 3) {
+        return Err(EvalError::OutOfCells);
+    }
+    let quote = cells.alloc_cell(CellType::Symbol(ns.quote));
+    let mut scaffold = vec![quote];
+    let mut form = NIL_INDEX;
+    for &arg in args.iter().rev() {
+        let quoted_tail = cons(arg, NIL_INDEX, cells);
+        let quoted = cons(quote, quoted_tail, cells);
+        form = cons(quoted, form, cells);
+        scaffold.extend_from_slice(&[quoted_tail, quoted, form]);
+    }
+    form = cons(f, form, cells);
+    scaffold.push(form);
+
+    let res = eval(form, cells, env, ns);
+    for idx in scaffold {
+        cells.release(idx);
+    }
+    res
+}
+
 fn eval_cons(exp: CellIndex,
              cells: &mut CellStorage,
              env: &mut Env,
@@ -33,11 +262,9 @@ fn eval_cons(exp: CellIndex,
         Err(EvalError::NonBinary)
     } else {
         let (head, tail) = split_binary(exp, cells);
-        let head = try!(eval(head, cells, env, ns));
-        let tail = try!(eval(tail, cells, env, ns));
-        let cons_cell = cells.alloc_cell(CellType::Cons(head));
-        cells.set_tail(cons_cell, tail);
-        Ok(cons_cell)
+        let head = eval(head, cells, env, ns)?;
+        let tail = eval(tail, cells, env, ns)?;
+        Ok(cons(head, tail, cells))
     }
 }
 
@@ -51,68 +278,3422 @@ fn eval_arithmetic(op: SymbolIndex,
         Err(EvalError::NonBinary)
     } else {
         let (head, tail) = split_binary(exp, cells);
-        let lhs = try!(eval(head, cells, env, ns));
-        let rhs = try!(eval(tail, cells, env, ns));
+        let lhs = eval(head, cells, env, ns)?;
+        let rhs = eval(tail, cells, env, ns)?;
         match (cells.val_of(lhs), cells.val_of(rhs)) {
             (CellType::Number(a), CellType::Number(b)) => {
-                Ok(cells.alloc_cell(CellType::Number(if op == ns.add {
-                    a + b
-                } else if op == ns.sub {
-                    a - b
-                } else if op == ns.mul {
-                    a * b
-                } else if op == ns.div {
-                    a / b
-                } else {
-                    // if op == ns.modu
-                    a % b
-                })))
+                let res = arithmetic(op, a, b, ns)?;
+                Ok(cells.alloc_cell(CellType::Number(res)))
             }
-            _ => Err(EvalError::NonNumeric),
+            (CellType::Number(_), _) => Err(at(EvalError::NonNumeric, tail, cells, env)),
+            _ => Err(at(EvalError::NonNumeric, head, cells, env)),
         }
     }
 }
 
-pub fn eval(exp: CellIndex,
-            cells: &mut CellStorage,
-            env: &mut Env,
-            ns: &DefaultNS)
-            -> Result<CellIndex, EvalError> {
-    let cell = cells.get(exp);
-    if is_atom(exp, cells) {
-        Ok(exp)
-    } else if let CellType::Cons(head) = cell.val {
-        if let CellType::Symbol(op) = cells.val_of(head) {
-            if op == ns.quote {
-                if !is_unary(exp, cells) {
-                    Err(EvalError::NonUnary)
-                } else {
-                    Ok(car!(cdr!(exp, cells), cells))
-                }
-            } else if op == ns.hd || op == ns.tl {
-                if !is_unary(exp, cells) {
-                    Err(EvalError::NonUnary)
-                } else {
-                    let res = try!(eval(car!(cdr!(exp, cells), cells), cells, env, ns));
-                    if !is_cons(res, cells) {
-                        Err(EvalError::NotCons(exp))
-                    } else if op == ns.hd {
-                        Ok(car!(res, cells))
-                    } else {
-                        Ok(cdr!(res, cells))
-                    }
-                }
-            } else if op == ns.cons {
-                eval_cons(exp, cells, env, ns)
-            } else if op == ns.add || op == ns.sub || op == ns.mul || op == ns.div || op == ns.modu {
-                eval_arithmetic(op, exp, cells, env, ns)
-            } else {
-                Err(EvalError::UnknownOperator(op))
+/// Applies the arithmetic builtin `op` to two numbers. Shared by `eval`
+/// and the bytecode machine so that the two can't disagree. `quotient` is
+/// another name for `div`, as `remainder` is for `mod`.
+pub fn arithmetic(op: SymbolIndex, a: i32, b: i32, ns: &DefaultNS) -> Result<i32, EvalError> {
+    let res = match op {
+        _ if op == ns.add => a.checked_add(b),
+        _ if op == ns.sub => a.checked_sub(b),
+        _ if op == ns.mul => a.checked_mul(b),
+        _ if op == ns.div || op == ns.quotient => a.checked_div(b),
+        _ if op == ns.modu || op == ns.remainder => a.checked_rem(b),
+        _ if op == ns.modulo => a.checked_rem(b).map(|rem| floor_mod(rem, b)),
+        _ => return Err(EvalError::UnknownOperator(op)),
+    };
+    // Adding, subtracting or multiplying by zero can't overflow.
+    res.ok_or(if b == 0 {
+        EvalError::DivideByZero
+    } else {
+        EvalError::Overflow
+    })
+}
+
+pub fn is_arithmetic(op: SymbolIndex, ns: &DefaultNS) -> bool {
+    op == ns.add || op == ns.sub || op == ns.mul || op == ns.div || op == ns.modu ||
+    op == ns.modulo || op == ns.remainder || op == ns.quotient
+}
+
+/// Turns the truncated remainder `rem` of a division by `divisor` into the
+/// floored one. `mod` and `remainder` give the sign of the dividend, as
+/// Rust's `%` does, while `modulo` gives the sign of the divisor.
+fn floor_mod(rem: i32, divisor: i32) -> i32 {
+    if rem != 0 && (rem < 0) != (divisor < 0) {
+        rem + divisor
+    } else {
+        rem
+    }
+}
+
+fn is_numeric_predicate(op: SymbolIndex, ns: &DefaultNS) -> bool {
+    op == ns.positive || op == ns.negative || op == ns.zero || op == ns.even || op == ns.odd
+}
+
+/// The canonical true value `t`, or `NIL_INDEX` for false.
+fn truth(holds: bool, cells: &mut CellStorage, ns: &DefaultNS) -> CellIndex {
+    if holds {
+        cells.alloc_cell(CellType::Symbol(ns.t))
+    } else {
+        NIL_INDEX
+    }
+}
+
+fn eval_numeric_predicate(op: SymbolIndex,
+                          exp: CellIndex,
+                          cells: &mut CellStorage,
+                          env: &mut Env,
+                          ns: &DefaultNS)
+                          -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let operand = car!(cdr!(exp, cells), cells);
+        let arg = eval(operand, cells, env, ns)?;
+        match cells.val_of(arg) {
+            CellType::Number(n) => {
+                Ok(truth(if op == ns.positive {
+                             n > 0
+                         } else if op == ns.negative {
+                             n < 0
+                         } else if op == ns.zero {
+                             n == 0
+                         } else if op == ns.even {
+                             n % 2 == 0
+                         } else {
+                             // if op == ns.odd
+                             n % 2 != 0
+                         },
+                         cells,
+                         ns))
             }
+            _ => Err(at(EvalError::NonNumeric, operand, cells, env)),
+        }
+    }
+}
+
+fn gcd(a: i32, b: i32) -> Option<i32> {
+    let (mut a, mut b) = (a.checked_abs()?, b.checked_abs()?);
+    while b != 0 {
+        let rem = a % b;
+        a = b;
+        b = rem;
+    }
+    Some(a)
+}
+
+fn lcm(a: i32, b: i32) -> Option<i32> {
+    if a == 0 || b == 0 {
+        Some(0)
+    } else {
+        (a / gcd(a, b)?).checked_mul(b)?.checked_abs()
+    }
+}
+
+/// Folds `gcd` or `lcm` over any number of arguments, starting from their
+/// identities 0 and 1.
+fn eval_gcd_lcm(op: SymbolIndex,
+                exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    let mut acc = if op == ns.gcd { 0 } else { 1 };
+    let mut args = cdr!(exp, cells);
+    while is_cons(args, cells) {
+        let n = eval_number(car!(args, cells), cells, env, ns)?;
+        acc = if op == ns.gcd { gcd(acc, n) } else { lcm(acc, n) }.ok_or(EvalError::Overflow)?;
+        args = cdr!(args, cells);
+    }
+    Ok(cells.alloc_cell(CellType::Number(acc)))
+}
+
+fn eval_lt(exp: CellIndex,
+           cells: &mut CellStorage,
+           env: &mut Env,
+           ns: &DefaultNS)
+           -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (head, tail) = split_binary(exp, cells);
+        let lhs = eval(head, cells, env, ns)?;
+        let rhs = eval(tail, cells, env, ns)?;
+        match (cells.val_of(lhs), cells.val_of(rhs)) {
+            (CellType::Number(a), CellType::Number(b)) => Ok(truth(a < b, cells, ns)),
+            (CellType::Number(_), _) => Err(at(EvalError::NonNumeric, tail, cells, env)),
+            _ => Err(at(EvalError::NonNumeric, head, cells, env)),
+        }
+    }
+}
+
+fn eval_random(exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    if !env.capabilities.random_and_clock {
+        Err(EvalError::PermissionDenied(ns.random))
+    } else if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let n = eval_number(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        if n <= 0 {
+            return Err(EvalError::NotPositive(n));
+        }
+        let res = env.rng.below(n as u32) as i32;
+        Ok(cells.alloc_cell(CellType::Number(res)))
+    }
+}
+
+/// Reseeding restarts the sequence, so the same seed always gives the
+/// same numbers. Returns the seed.
+fn eval_set_seed(exp: CellIndex,
+                 cells: &mut CellStorage,
+                 env: &mut Env,
+                 ns: &DefaultNS)
+                 -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let seed = eval_number(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        env.rng = Rng::new(seed as u64);
+        Ok(cells.alloc_cell(CellType::Number(seed)))
+    }
+}
+
+/// `cells-used` counts the cell its answer is about to take, which is none
+/// if the answer is interned, and `cells-free` counts before taking one, so
+/// that `(add (cells-used) (cells-free))` is the capacity.
+fn eval_cell_counts(op: SymbolIndex,
+                    exp: CellIndex,
+                    cells: &mut CellStorage,
+                    ns: &DefaultNS)
+                    -> Result<CellIndex, EvalError> {
+    if cdr!(exp, cells) != NIL_INDEX {
+        return Err(EvalError::ArgCount {
+            expected: 0,
+            got: count_args(exp, cells),
+        });
+    }
+    if op == ns.cells_used {
+        let used = (cells.capacity() - cells.free_count()) as i32;
+        let with_res = used + 1;
+        let used = if (SMALL_INT_MIN..SMALL_INT_END).contains(&with_res) {
+            used
         } else {
-            Err(EvalError::IllegalOperator)
+            with_res
+        };
+        Ok(cells.alloc_cell(CellType::Number(used)))
+    } else {
+        let free = cells.free_count();
+        Ok(cells.alloc_cell(CellType::Number(free as i32)))
+    }
+}
+
+/// Milliseconds since the interpreter started, sticking at `i32::MAX`
+/// after about 24 days.
+fn eval_time_ms(exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    if !env.capabilities.random_and_clock {
+        return Err(EvalError::PermissionDenied(ns.time_ms));
+    }
+    if cdr!(exp, cells) != NIL_INDEX {
+        return Err(EvalError::ArgCount {
+            expected: 0,
+            got: count_args(exp, cells),
+        });
+    }
+    let ms = cmp::min(env.elapsed_ms(), i32::MAX as u128) as i32;
+    Ok(cells.alloc_cell(CellType::Number(ms)))
+}
+
+fn is_bitwise(op: SymbolIndex, ns: &DefaultNS) -> bool {
+    op == ns.bit_and || op == ns.bit_or || op == ns.bit_xor || op == ns.shl || op == ns.shr
+}
+
+/// The binary bitwise operators. Shifts have to be by 0 to 31 bits.
+fn eval_bitwise(op: SymbolIndex,
+                exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (head, tail) = split_binary(exp, cells);
+        let a = eval_number(head, cells, env, ns)?;
+        let b = eval_number(tail, cells, env, ns)?;
+        let res = if op == ns.bit_and {
+            a & b
+        } else if op == ns.bit_or {
+            a | b
+        } else if op == ns.bit_xor {
+            a ^ b
+        } else if !(0..32).contains(&b) {
+            return Err(EvalError::BadShift(b));
+        } else if op == ns.shl {
+            a << b
+        } else {
+            // if op == ns.shr
+            a >> b
+        };
+        Ok(cells.alloc_cell(CellType::Number(res)))
+    }
+}
+
+fn eval_bit_not(exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let n = eval_number(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        Ok(cells.alloc_cell(CellType::Number(!n)))
+    }
+}
+
+/// Whether `op` uses some of its operands as they were written rather than
+/// evaluating them, so that folding them would show.
+fn keeps_operands(op: SymbolIndex, env: &Env, ns: &DefaultNS) -> bool {
+    op == ns.quote || op == ns.defmacro || op == ns.define_test || op == ns.compile ||
+    op == ns.delay || op == ns.macroexpand || op == ns.assert || env.get_macro(op).is_some()
+}
+
+/// Works out the arithmetic on number literals inside `exp` ahead of time,
+/// returning the value of `exp` if it is constant. `eval` then uses the
+/// values recorded in `env` instead of doing the arithmetic, while the forms
+/// stay as written so that error messages and context don't change. Quoted
+/// data, operands that aren't evaluated (including those of macros defined
+/// so far) and anything that isn't entirely constant are left alone.
+pub fn fold_constants(exp: CellIndex,
+                      cells: &CellStorage,
+                      env: &mut Env,
+                      ns: &DefaultNS)
+                      -> Option<i32> {
+    let op = match cells.val_of(exp) {
+        CellType::Number(n) => return Some(n),
+        CellType::Cons(head) => {
+            match cells.val_of(head) {
+                CellType::Symbol(op) => Some(op),
+                _ => None,
+            }
+        }
+        _ => return None,
+    };
+    if op.is_some_and(|op| keeps_operands(op, env, ns)) {
+        return None;
+    }
+
+    let mut operands = Vec::new();
+    let mut spine = cdr!(exp, cells);
+    while is_cons(spine, cells) {
+        operands.push(fold_constants(car!(spine, cells), cells, env, ns));
+        spine = cdr!(spine, cells);
+    }
+    if op.is_none() {
+        fold_constants(car!(exp, cells), cells, env, ns);
+    }
+
+    match (op, operands.as_slice()) {
+        (Some(op), &[Some(a), Some(b)]) if is_arithmetic(op, ns) && spine == NIL_INDEX => {
+            // Errors are left for `eval` to raise.
+            let val = arithmetic(op, a, b, ns).ok()?;
+            env.set_folded(exp, val);
+            Some(val)
+        }
+        _ => None,
+    }
+}
+
+/// Counts the distinct cons cells reachable from `exp`, so that shared
+/// structure is only counted once.
+fn count_cells(exp: CellIndex, cells: &CellStorage) -> usize {
+    let mut visited = HashSet::new();
+    let mut pending = vec![exp];
+    while let Some(idx) = pending.pop() {
+        if is_cons(idx, cells) && visited.insert(idx) {
+            pending.push(car!(idx, cells));
+            pending.push(cdr!(idx, cells));
         }
+    }
+    visited.len()
+}
+
+fn eval_count_cells(exp: CellIndex,
+                    cells: &mut CellStorage,
+                    env: &mut Env,
+                    ns: &DefaultNS)
+                    -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
     } else {
-        panic!("Invalid expression")
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let count = count_cells(arg, cells);
+        Ok(cells.alloc_cell(CellType::Number(count as i32)))
+    }
+}
+
+/// Evaluates `operand`, which has to produce a number.
+fn eval_number(operand: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<i32, EvalError> {
+    let res = eval(operand, cells, env, ns)?;
+    match cells.val_of(res) {
+        CellType::Number(n) => Ok(n),
+        _ => Err(at(EvalError::NonNumeric, operand, cells, env)),
+    }
+}
+
+/// Evaluates `operand`, which has to produce a non-negative number.
+fn eval_count(operand: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<usize, EvalError> {
+    match eval_number(operand, cells, env, ns)? {
+        n if n < 0 => Err(EvalError::NegativeCount(n)),
+        n => Ok(n as usize),
+    }
+}
+
+fn eval_make_list(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (count, fill) = split_binary(exp, cells);
+        let count = eval_count(count, cells, env, ns)?;
+        // Every element shares the one evaluated fill cell.
+        let fill = eval(fill, cells, env, ns)?;
+        let mut list = NIL_INDEX;
+        for _ in 0..count {
+            list = try_cons(fill, list, cells)?;
+        }
+        Ok(list)
+    }
+}
+
+/// `take` copies the first `n` cells of the list, while `drop` shares the
+/// rest of it. Running off the end of the list isn't an error.
+fn eval_take_drop(op: SymbolIndex,
+                  exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (count, list) = split_binary(exp, cells);
+        let count = eval_count(count, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let mut taken = Vec::new();
+        while taken.len() < count && is_cons(list, cells) {
+            taken.push(car!(list, cells));
+            list = cdr!(list, cells);
+        }
+        if op == ns.take {
+            Ok(list_of(&taken, cells))
+        } else {
+            Ok(list)
+        }
+    }
+}
+
+/// Like `take` and `drop`, but the leading run is as long as the predicate
+/// holds rather than a fixed count. `take-while` copies, `drop-while` shares.
+fn eval_take_drop_while(op: SymbolIndex,
+                        exp: CellIndex,
+                        cells: &mut CellStorage,
+                        env: &mut Env,
+                        ns: &DefaultNS)
+                        -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (pred, list) = split_binary(exp, cells);
+        let pred = eval(pred, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let mut taken = Vec::new();
+        while is_cons(list, cells) {
+            let item = car!(list, cells);
+            let res = apply(pred, &[item], cells, env, ns)?;
+            if !is_truthy(res, cells, env) {
+                break;
+            }
+            taken.push(item);
+            list = cdr!(list, cells);
+        }
+        if op == ns.take_while {
+            Ok(list_of(&taken, cells))
+        } else {
+            Ok(list)
+        }
+    }
+}
+
+fn eval_partition(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (pred, list) = split_binary(exp, cells);
+        let pred = eval(pred, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let (mut yes, mut no) = (Vec::new(), Vec::new());
+        while is_cons(list, cells) {
+            let item = car!(list, cells);
+            let res = apply(pred, &[item], cells, env, ns)?;
+            if is_truthy(res, cells, env) {
+                yes.push(item);
+            } else {
+                no.push(item);
+            }
+            list = cdr!(list, cells);
+        }
+        let yes = list_of(&yes, cells);
+        let no = list_of(&no, cells);
+        Ok(list_of(&[yes, no], cells))
+    }
+}
+
+fn eval_count_if(exp: CellIndex,
+                 cells: &mut CellStorage,
+                 env: &mut Env,
+                 ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (pred, list) = split_binary(exp, cells);
+        let pred = eval(pred, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let mut count = 0;
+        while is_cons(list, cells) {
+            let res = apply(pred, &[car!(list, cells)], cells, env, ns)?;
+            if is_truthy(res, cells, env) {
+                count += 1;
+            }
+            list = cdr!(list, cells);
+        }
+        Ok(cells.alloc_cell(CellType::Number(count)))
+    }
+}
+
+/// `any` and `every` stop at the first element that settles the answer, so
+/// later elements are never passed to the predicate.
+fn eval_any_every(op: SymbolIndex,
+                  exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (pred, list) = split_binary(exp, cells);
+        let pred = eval(pred, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        while is_cons(list, cells) {
+            let res = apply(pred, &[car!(list, cells)], cells, env, ns)?;
+            match (op == ns.any, is_truthy(res, cells, env)) {
+                (true, true) => return Ok(res),
+                (false, false) => return Ok(NIL_INDEX),
+                _ => list = cdr!(list, cells),
+            }
+        }
+        Ok(truth(op == ns.every, cells, ns))
+    }
+}
+
+fn eval_range(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    let (start, end) = if is_unary(exp, cells) {
+        (0, eval_number(car!(cdr!(exp, cells), cells), cells, env, ns)?)
+    } else if is_binary(exp, cells) {
+        let (start, end) = split_binary(exp, cells);
+        (eval_number(start, cells, env, ns)?, eval_number(end, cells, env, ns)?)
+    } else {
+        return Err(EvalError::NonBinary);
+    };
+    let mut list = NIL_INDEX;
+    for n in (start..end).rev() {
+        let item = cells.try_alloc_cell(CellType::Number(n)).ok_or(EvalError::OutOfCells)?;
+        list = try_cons(item, list, cells)?;
+    }
+    Ok(list)
+}
+
+fn eval_list_tabulate(exp: CellIndex,
+                      cells: &mut CellStorage,
+                      env: &mut Env,
+                      ns: &DefaultNS)
+                      -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (count, f) = split_binary(exp, cells);
+        let count = eval_count(count, cells, env, ns)?;
+        let f = eval(f, cells, env, ns)?;
+        let mut results = Vec::with_capacity(count);
+        for i in 0..count {
+            let i = cells.try_alloc_cell(CellType::Number(i as i32)).ok_or(EvalError::OutOfCells)?;
+            results.push(apply(f, &[i], cells, env, ns)?);
+        }
+        let mut list = NIL_INDEX;
+        for &res in results.iter().rev() {
+            list = try_cons(res, list, cells)?;
+        }
+        Ok(list)
+    }
+}
+
+/// Collects `(f seed)` for `seed`, `(next seed)`, `(next (next seed))` and
+/// so on, stopping before the first seed for which `stop` holds. The step
+/// limit is the only guard against a `stop` that never holds.
+fn eval_unfold(exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    let got = count_args(exp, cells);
+    if got != 4 {
+        return Err(EvalError::ArgCount {
+            expected: 4,
+            got,
+        });
+    }
+    let (stop, f, seed) = split_ternary(exp, cells);
+    let next = car!(cdr!(cdr!(cdr!(cdr!(exp, cells), cells), cells), cells), cells);
+    let stop = eval(stop, cells, env, ns)?;
+    let f = eval(f, cells, env, ns)?;
+    let mut seed = eval(seed, cells, env, ns)?;
+    let next = eval(next, cells, env, ns)?;
+    let mut results = Vec::new();
+    loop {
+        let done = apply(stop, &[seed], cells, env, ns)?;
+        if is_truthy(done, cells, env) {
+            return Ok(list_of(&results, cells));
+        }
+        results.push(apply(f, &[seed], cells, env, ns)?);
+        seed = apply(next, &[seed], cells, env, ns)?;
+    }
+}
+
+/// Lists the bytecode for the unevaluated argument, one `(push n)` or
+/// `(op)` per instruction.
+fn eval_compile(exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        return Err(EvalError::NonUnary);
+    }
+    let code = vm::compile(car!(cdr!(exp, cells), cells), cells, ns)?;
+    let push = env.add_sym("push".to_string());
+    let mut listing = Vec::with_capacity(code.len());
+    for op in code {
+        let insn = match op {
+            Op::Push(n) => {
+                let push = cells.alloc_cell(CellType::Symbol(push));
+                let n = cells.alloc_cell(CellType::Number(n));
+                list_of(&[push, n], cells)
+            }
+            Op::Arith(op) => {
+                let op = cells.alloc_cell(CellType::Symbol(op));
+                list_of(&[op], cells)
+            }
+        };
+        listing.push(insn);
+    }
+    Ok(list_of(&listing, cells))
+}
+
+fn eval_map2(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_ternary(exp, cells) {
+        Err(EvalError::NonTernary)
+    } else {
+        let (f, lhs, rhs) = split_ternary(exp, cells);
+        let f = eval(f, cells, env, ns)?;
+        let mut lhs = eval(lhs, cells, env, ns)?;
+        let mut rhs = eval(rhs, cells, env, ns)?;
+        let mut results = Vec::new();
+        while is_cons(lhs, cells) && is_cons(rhs, cells) {
+            let args = [car!(lhs, cells), car!(rhs, cells)];
+            results.push(apply(f, &args, cells, env, ns)?);
+            lhs = cdr!(lhs, cells);
+            rhs = cdr!(rhs, cells);
+        }
+        Ok(list_of(&results, cells))
+    }
+}
+
+/// Copies every cons cell reachable from `exp`. Atoms aren't copied, and
+/// structure shared within `exp` comes out as separate copies.
+fn deep_copy(exp: CellIndex, cells: &mut CellStorage) -> CellIndex {
+    let mut heads = Vec::new();
+    let mut spine = exp;
+    while is_cons(spine, cells) {
+        heads.push(car!(spine, cells));
+        spine = cdr!(spine, cells);
+    }
+    heads.iter().rev().fold(spine, |tail, &head| {
+        let head = deep_copy(head, cells);
+        cons(head, tail, cells)
+    })
+}
+
+fn eval_copy(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        Ok(deep_copy(arg, cells))
+    }
+}
+
+/// Copies only the spine of `exp`, so the elements and any improper tail are
+/// shared with the original. Atoms come back as they are.
+fn eval_list_copy(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let mut list = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let mut heads = Vec::new();
+        while is_cons(list, cells) {
+            heads.push(car!(list, cells));
+            list = cdr!(list, cells);
+        }
+        Ok(heads.iter().rev().fold(list, |tail, &head| cons(head, tail, cells)))
+    }
+}
+
+fn eval_draw(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let picture = draw_exp(arg, cells, env);
+        env.print_line(&picture);
+        Ok(arg)
+    }
+}
+
+fn eval_pp(exp: CellIndex,
+           cells: &mut CellStorage,
+           env: &mut Env,
+           ns: &DefaultNS)
+           -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let text = pretty_exp(arg, cells, env, PP_WIDTH);
+        env.print_line(&text);
+        Ok(arg)
+    }
+}
+
+/// Stable merge sort, calling the fallible `less` once per comparison.
+fn merge_sort<F>(items: &[CellIndex], less: &mut F) -> Result<Vec<CellIndex>, EvalError>
+    where F: FnMut(CellIndex, CellIndex) -> Result<bool, EvalError>
+{
+    if items.len() <= 1 {
+        return Ok(items.to_vec());
+    }
+    let (left, right) = items.split_at(items.len() / 2);
+    let left = merge_sort(left, less)?;
+    let right = merge_sort(right, less)?;
+
+    let mut merged = Vec::with_capacity(items.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        // Only take from the right when it's strictly less, so ties keep
+        // their original order.
+        if less(right[j], left[i])? {
+            merged.push(right[j]);
+            j += 1;
+        } else {
+            merged.push(left[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+    Ok(merged)
+}
+
+fn eval_sort(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (list, less) = split_binary(exp, cells);
+        let mut list = eval(list, cells, env, ns)?;
+        let less = eval(less, cells, env, ns)?;
+        let mut items = Vec::new();
+        while is_cons(list, cells) {
+            items.push(car!(list, cells));
+            list = cdr!(list, cells);
+        }
+        let sorted = merge_sort(&items, &mut |a, b| {
+            let res = apply(less, &[a, b], cells, env, ns)?;
+            Ok(is_truthy(res, cells, env))
+        })?;
+        Ok(list_of(&sorted, cells))
+    }
+}
+
+/// Folds from the right, so `f` first sees the last element and `init`.
+fn eval_fold_right(exp: CellIndex,
+                   cells: &mut CellStorage,
+                   env: &mut Env,
+                   ns: &DefaultNS)
+                   -> Result<CellIndex, EvalError> {
+    if !is_ternary(exp, cells) {
+        Err(EvalError::NonTernary)
+    } else {
+        let (f, init, list) = split_ternary(exp, cells);
+        let f = eval(f, cells, env, ns)?;
+        let mut acc = eval(init, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let mut items = Vec::new();
+        while is_cons(list, cells) {
+            items.push(car!(list, cells));
+            list = cdr!(list, cells);
+        }
+        for &item in items.iter().rev() {
+            acc = apply(f, &[item, acc], cells, env, ns)?;
+        }
+        Ok(acc)
+    }
+}
+
+/// Folds from the left, seeded with the first element since there's no
+/// initial value. An empty list has nothing to seed with and is an error.
+fn eval_reduce(exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (f, operand) = split_binary(exp, cells);
+        let f = eval(f, cells, env, ns)?;
+        let list = eval(operand, cells, env, ns)?;
+        if !is_cons(list, cells) {
+            return Err(EvalError::NotCons(operand));
+        }
+        let mut acc = car!(list, cells);
+        let mut list = cdr!(list, cells);
+        while is_cons(list, cells) {
+            acc = apply(f, &[acc, car!(list, cells)], cells, env, ns)?;
+            list = cdr!(list, cells);
+        }
+        Ok(acc)
+    }
+}
+
+fn eval_last(op: SymbolIndex,
+             exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let operand = car!(cdr!(exp, cells), cells);
+        let mut pair = eval(operand, cells, env, ns)?;
+        if !is_cons(pair, cells) {
+            return Err(EvalError::NotCons(operand));
+        }
+        while is_cons(cdr!(pair, cells), cells) {
+            pair = cdr!(pair, cells);
+        }
+        if op == ns.last_pair {
+            Ok(pair)
+        } else {
+            Ok(car!(pair, cells))
+        }
+    }
+}
+
+fn eval_vector(op: SymbolIndex,
+               exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    let mut items = Vec::new();
+    if op == ns.vector {
+        let mut args = cdr!(exp, cells);
+        while is_cons(args, cells) {
+            items.push(eval(car!(args, cells), cells, env, ns)?);
+            args = cdr!(args, cells);
+        }
+    } else if !is_unary(exp, cells) {
+        return Err(EvalError::NonUnary);
+    } else {
+        let mut list = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        while is_cons(list, cells) {
+            items.push(car!(list, cells));
+            list = cdr!(list, cells);
+        }
+    }
+    let vec = env.add_vector(items);
+    Ok(cells.alloc_cell(CellType::Vector(vec)))
+}
+
+/// Evaluates `operand`, which has to give a vector.
+fn eval_vector_operand(operand: CellIndex,
+                       cells: &mut CellStorage,
+                       env: &mut Env,
+                       ns: &DefaultNS)
+                       -> Result<VectorIndex, EvalError> {
+    let res = eval(operand, cells, env, ns)?;
+    match cells.val_of(res) {
+        CellType::Vector(vec) => Ok(vec),
+        _ => Err(EvalError::NotVector(operand)),
+    }
+}
+
+fn eval_vector_ref(exp: CellIndex,
+                   cells: &mut CellStorage,
+                   env: &mut Env,
+                   ns: &DefaultNS)
+                   -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (vec, index) = split_binary(exp, cells);
+        let vec = eval_vector_operand(vec, cells, env, ns)?;
+        let index = eval_number(index, cells, env, ns)?;
+        let items = env.get_vector(vec);
+        if index < 0 || index as usize >= items.len() {
+            Err(EvalError::IndexOutOfRange(index))
+        } else {
+            Ok(items[index as usize])
+        }
+    }
+}
+
+/// Replaces an element of the vector in place, returning the new value.
+fn eval_vector_set(exp: CellIndex,
+                   cells: &mut CellStorage,
+                   env: &mut Env,
+                   ns: &DefaultNS)
+                   -> Result<CellIndex, EvalError> {
+    if !is_ternary(exp, cells) {
+        Err(EvalError::NonTernary)
+    } else {
+        let (vec, index, val) = split_ternary(exp, cells);
+        let vec = eval_vector_operand(vec, cells, env, ns)?;
+        let index = eval_number(index, cells, env, ns)?;
+        let val = eval(val, cells, env, ns)?;
+        let items = env.get_vector_mut(vec);
+        if index < 0 || index as usize >= items.len() {
+            Err(EvalError::IndexOutOfRange(index))
+        } else {
+            items[index as usize] = val;
+            Ok(val)
+        }
+    }
+}
+
+fn eval_vector_length(exp: CellIndex,
+                      cells: &mut CellStorage,
+                      env: &mut Env,
+                      ns: &DefaultNS)
+                      -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let vec = eval_vector_operand(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let len = env.get_vector(vec).len() as i32;
+        Ok(cells.alloc_cell(CellType::Number(len)))
+    }
+}
+
+/// Evaluates `operand`, which has to give a hash table.
+fn eval_hash_table_operand(operand: CellIndex,
+                           cells: &mut CellStorage,
+                           env: &mut Env,
+                           ns: &DefaultNS)
+                           -> Result<HashTableIndex, EvalError> {
+    let res = eval(operand, cells, env, ns)?;
+    match cells.val_of(res) {
+        CellType::HashTable(table) => Ok(table),
+        _ => Err(EvalError::NotHashTable(operand)),
+    }
+}
+
+/// Evaluates `operand`, which has to give a number or a symbol.
+fn eval_hash_key(operand: CellIndex,
+                 cells: &mut CellStorage,
+                 env: &mut Env,
+                 ns: &DefaultNS)
+                 -> Result<HashKey, EvalError> {
+    let res = eval(operand, cells, env, ns)?;
+    match cells.val_of(res) {
+        CellType::Number(n) => Ok(HashKey::Number(n)),
+        CellType::Symbol(sym) => Ok(HashKey::Symbol(sym)),
+        _ => Err(EvalError::Unhashable(operand)),
+    }
+}
+
+/// `hash-set!` returns the value it stored and `hash-remove!` the value it
+/// removed, or `()` if there wasn't one.
+fn eval_hash(op: SymbolIndex,
+             exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if op == ns.make_hash {
+        if cdr!(exp, cells) != NIL_INDEX {
+            return Err(EvalError::ArgCount {
+                expected: 0,
+                got: count_args(exp, cells),
+            });
+        }
+        let table = env.add_hash_table();
+        Ok(cells.alloc_cell(CellType::HashTable(table)))
+    } else if op == ns.hash_remove {
+        if !is_binary(exp, cells) {
+            return Err(EvalError::NonBinary);
+        }
+        let (table, key) = split_binary(exp, cells);
+        let table = eval_hash_table_operand(table, cells, env, ns)?;
+        let key = eval_hash_key(key, cells, env, ns)?;
+        Ok(env.get_hash_table_mut(table).remove(&key).unwrap_or(NIL_INDEX))
+    } else if !is_ternary(exp, cells) {
+        Err(EvalError::NonTernary)
+    } else {
+        let (table, key, val) = split_ternary(exp, cells);
+        let table = eval_hash_table_operand(table, cells, env, ns)?;
+        let key = eval_hash_key(key, cells, env, ns)?;
+        if op == ns.hash_set {
+            let val = eval(val, cells, env, ns)?;
+            env.get_hash_table_mut(table).insert(key, val);
+            Ok(val)
+        } else {
+            // if op == ns.hash_ref
+            match env.get_hash_table(table).get(&key) {
+                Some(&found) => Ok(found),
+                None => eval(val, cells, env, ns),
+            }
+        }
+    }
+}
+
+/// Releases the cells a failed evaluation allocated, as recorded by
+/// `CellStorage::start_tracking`, along with the `env` entries of vectors,
+/// hash tables and promises among them. None of them can be part of a
+/// result, but macros and tests defined before the failure are kept, as is
+/// anything stored into a vector, hash table or promise that was made
+/// before the evaluation.
+pub fn reclaim(allocated: Vec<CellIndex>, cells: &mut CellStorage, env: &mut Env) {
+    let (mut vectors, mut tables, mut promises) = (HashSet::new(), HashSet::new(), HashSet::new());
+    for &idx in allocated.iter() {
+        match cells.val_of(idx) {
+            CellType::Vector(vec) => {
+                vectors.insert(vec);
+            }
+            CellType::HashTable(table) => {
+                tables.insert(table);
+            }
+            CellType::Promise(promise) => {
+                promises.insert(promise);
+            }
+            _ => {}
+        }
+    }
+    let mut kept = HashSet::new();
+    let mut pending = env.persistent_cells();
+    pending.extend(env.held_cells(&vectors, &tables, &promises));
+    while let Some(idx) = pending.pop() {
+        if idx == NIL_INDEX || cells.is_interned(idx) || !kept.insert(idx) {
+            continue;
+        }
+        match cells.val_of(idx) {
+            CellType::Cons(head) => {
+                pending.push(head);
+                pending.push(cells.tail_of(idx));
+            }
+            CellType::Vector(vec) => pending.extend_from_slice(env.get_vector(vec)),
+            CellType::HashTable(table) => pending.extend(env.get_hash_table(table).values()),
+            CellType::Promise(promise) => {
+                let promise = env.get_promise(promise);
+                pending.push(promise.expr);
+                pending.extend(promise.value);
+            }
+            _ => {}
+        }
+    }
+    let mut allocated = allocated;
+    allocated.sort();
+    allocated.dedup();
+    for idx in allocated {
+        if kept.contains(&idx) {
+            continue;
+        }
+        match cells.val_of(idx) {
+            CellType::Free => continue,
+            CellType::Vector(vec) => {
+                env.remove_vector(vec);
+            }
+            CellType::HashTable(table) => {
+                env.remove_hash_table(table);
+            }
+            CellType::Promise(promise) => {
+                env.remove_promise(promise);
+            }
+            _ => {}
+        }
+        cells.release(idx);
+    }
+}
+
+/// Copies every cell of `exp`, atoms included, so that the copy outlives
+/// the input it came from.
+fn copy_tree(exp: CellIndex,
+             depth: usize,
+             cells: &mut CellStorage)
+             -> Result<CellIndex, EvalError> {
+    if depth > MAX_DEPTH {
+        return Err(EvalError::TooDeep);
+    }
+    if exp == NIL_INDEX {
+        Ok(NIL_INDEX)
+    } else if let CellType::Cons(head) = cells.val_of(exp) {
+        let head = copy_tree(head, depth + 1, cells)?;
+        let tail = copy_tree(cdr!(exp, cells), depth + 1, cells)?;
+        Ok(cons(head, tail, cells))
+    } else {
+        let val = cells.val_of(exp);
+        Ok(cells.alloc_cell(val))
+    }
+}
+
+/// Collects the symbols of a parameter list.
+fn macro_params(params: CellIndex, cells: &CellStorage) -> Option<Vec<SymbolIndex>> {
+    let mut syms = Vec::new();
+    let mut params = params;
+    while is_cons(params, cells) {
+        match cells.val_of(car!(params, cells)) {
+            CellType::Symbol(sym) => syms.push(sym),
+            _ => return None,
+        }
+        params = cdr!(params, cells);
+    }
+    if params == NIL_INDEX {
+        Some(syms)
+    } else {
+        None
+    }
+}
+
+/// Keeps a copy of the template, since the form it was read from is
+/// freed once evaluated.
+fn eval_defmacro(exp: CellIndex,
+                 cells: &mut CellStorage,
+                 env: &mut Env)
+                 -> Result<CellIndex, EvalError> {
+    if !is_ternary(exp, cells) {
+        return Err(EvalError::NonTernary);
+    }
+    let (name, params, template) = split_ternary(exp, cells);
+    let name = match cells.val_of(name) {
+        CellType::Symbol(sym) => sym,
+        _ => return Err(EvalError::BadMacro(exp)),
+    };
+    let params = macro_params(params, cells).ok_or(EvalError::BadMacro(exp))?;
+    let template = copy_tree(template, 0, cells)?;
+    env.define_macro(name, Macro { params, template });
+    Ok(cells.alloc_cell(CellType::Symbol(name)))
+}
+
+/// Builds `template` over again with each parameter symbol replaced by its
+/// argument. The arguments themselves are shared, not copied, but every
+/// other atom is: the expansion gets freed after use and must not take the
+/// template's cells with it.
+fn substitute(template: CellIndex,
+              bindings: &[(SymbolIndex, CellIndex)],
+              depth: usize,
+              cells: &mut CellStorage)
+              -> Result<CellIndex, EvalError> {
+    if depth > MAX_DEPTH {
+        return Err(EvalError::TooDeep);
+    }
+    match cells.val_of(template) {
+        CellType::Symbol(sym) => {
+            match bindings.iter().find(|binding| binding.0 == sym) {
+                Some(binding) => Ok(binding.1),
+                None => Ok(cells.alloc_cell(CellType::Symbol(sym))),
+            }
+        }
+        CellType::Number(n) => Ok(cells.alloc_cell(CellType::Number(n))),
+        CellType::Cons(head) => {
+            let head = substitute(head, bindings, depth + 1, cells)?;
+            let tail = substitute(cdr!(template, cells), bindings, depth + 1, cells)?;
+            Ok(cons(head, tail, cells))
+        }
+        _ => Ok(template),
+    }
+}
+
+/// The form that a use of the macro `name` stands for.
+fn expand_macro(name: SymbolIndex,
+                exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &Env)
+                -> Result<CellIndex, EvalError> {
+    let mac = env.get_macro(name).unwrap();
+    let mut args = Vec::new();
+    let mut rest = cdr!(exp, cells);
+    while is_cons(rest, cells) {
+        args.push(car!(rest, cells));
+        rest = cdr!(rest, cells);
+    }
+    if args.len() != mac.params.len() {
+        return Err(EvalError::ArgCount {
+            expected: mac.params.len(),
+            got: args.len(),
+        });
+    }
+    let bindings: Vec<_> = mac.params.iter().cloned().zip(args).collect();
+    substitute(mac.template, &bindings, 0, cells)
+}
+
+fn eval_symbols(exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env)
+                -> Result<CellIndex, EvalError> {
+    if cdr!(exp, cells) != NIL_INDEX {
+        return Err(EvalError::ArgCount {
+            expected: 0,
+            got: count_args(exp, cells),
+        });
+    }
+    // There are a couple of hundred symbols, which may not fit in storage.
+    let mut list = NIL_INDEX;
+    for sym in (0..env.symbol_count()).rev() {
+        let head = cells.try_alloc_cell(CellType::Symbol(sym)).ok_or(EvalError::OutOfCells)?;
+        let pair = cells.try_alloc_cell(CellType::Cons(head)).ok_or(EvalError::OutOfCells)?;
+        cells.set_tail(pair, list);
+        list = pair;
+    }
+    Ok(list)
+}
+
+/// Whether `sym` names something: builtins and macros are the only
+/// bindings. Strict mode and `bound?` both go by this.
+fn is_bound(sym: SymbolIndex, env: &Env, ns: &DefaultNS) -> bool {
+    ns.is_builtin(sym) || env.get_macro(sym).is_some()
+}
+
+/// The name isn't evaluated, so asking about an unbound symbol is fine
+/// even in strict mode.
+fn eval_bound(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let bound = match cells.val_of(car!(cdr!(exp, cells), cells)) {
+            CellType::Symbol(sym) => is_bound(sym, env, ns),
+            _ => false,
+        };
+        Ok(truth(bound, cells, ns))
+    }
+}
+
+/// Expands the evaluated form once if it's a use of a macro, without
+/// evaluating the expansion. Anything else comes back as it was.
+fn eval_macroexpand(exp: CellIndex,
+                    cells: &mut CellStorage,
+                    env: &mut Env,
+                    ns: &DefaultNS)
+                    -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        return Err(EvalError::NonUnary);
+    }
+    let form = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+    if is_cons(form, cells) {
+        if let CellType::Symbol(name) = cells.val_of(car!(form, cells)) {
+            if env.get_macro(name).is_some() {
+                return expand_macro(name, form, cells, env);
+            }
+        }
+    }
+    Ok(form)
+}
+
+/// There are no variables for a promise to close over, so it holds just a
+/// copy of its expression.
+fn eval_delay(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env)
+              -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        return Err(EvalError::NonUnary);
+    }
+    let expr = copy_tree(car!(cdr!(exp, cells), cells), 0, cells)?;
+    let promise = env.add_promise(expr);
+    Ok(cells.alloc_cell(CellType::Promise(promise)))
+}
+
+/// Evaluates a promise's expression the first time, remembering the value
+/// for later. Forcing anything other than a promise gives it back as is.
+fn eval_force(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        return Err(EvalError::NonUnary);
+    }
+    let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+    let promise = match cells.val_of(arg) {
+        CellType::Promise(promise) => promise,
+        _ => return Ok(arg),
+    };
+    if let Some(value) = env.get_promise(promise).value {
+        return Ok(value);
+    }
+    let value = eval(env.get_promise(promise).expr, cells, env, ns)?;
+    env.get_promise_mut(promise).value = Some(value);
+    Ok(value)
+}
+
+/// Prints the description, like `draw` does, and returns `t`, or `()` when
+/// the argument isn't a builtin with help.
+fn eval_help(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let text = match cells.val_of(arg) {
+            CellType::Symbol(sym) => ns.help(sym),
+            _ => None,
+        };
+        env.print_line(text.unwrap_or("no help available"));
+        Ok(truth(text.is_some(), cells, ns))
+    }
+}
+
+/// Identity comparison: numbers and symbols by value, pairs by cell.
+fn is_eq(a: CellIndex, b: CellIndex, cells: &CellStorage) -> bool {
+    match (cells.val_of(a), cells.val_of(b)) {
+        (CellType::Number(x), CellType::Number(y)) => x == y,
+        (CellType::Symbol(x), CellType::Symbol(y)) => x == y,
+        _ => a == b,
+    }
+}
+
+/// Structural equality: conses are equal when their heads and tails are,
+/// everything else is compared with `is_eq`.
+fn is_equal(a: CellIndex, b: CellIndex, cells: &CellStorage) -> bool {
+    let mut pending = vec![(a, b)];
+    while let Some((a, b)) = pending.pop() {
+        if is_cons(a, cells) && is_cons(b, cells) {
+            pending.push((cdr!(a, cells), cdr!(b, cells)));
+            pending.push((car!(a, cells), car!(b, cells)));
+        } else if !is_eq(a, b, cells) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Both build a new list of the elements that don't match, even when
+/// nothing is removed. `remove` compares with `is_equal`, `remq` with `is_eq`.
+fn eval_remove(op: SymbolIndex,
+               exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (key, list) = split_binary(exp, cells);
+        let key = eval(key, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        let mut kept = Vec::new();
+        while is_cons(list, cells) {
+            let item = car!(list, cells);
+            let matches = if op == ns.remove {
+                is_equal(key, item, cells)
+            } else {
+                is_eq(key, item, cells)
+            };
+            if !matches {
+                kept.push(item);
+            }
+            list = cdr!(list, cells);
+        }
+        Ok(list_of(&kept, cells))
+    }
+}
+
+/// Leaves `alist` alone: the result has a new spine, with the first pair
+/// whose head `is_equal` the key replaced, or a new pair in front if there
+/// is none. The other pairs are shared.
+fn eval_assoc_set(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_ternary(exp, cells) {
+        Err(EvalError::NonTernary)
+    } else {
+        let (alist, key, val) = split_ternary(exp, cells);
+        let mut alist = eval(alist, cells, env, ns)?;
+        let key = eval(key, cells, env, ns)?;
+        let val = eval(val, cells, env, ns)?;
+        let pair = cons(key, val, cells);
+        let mut pairs = Vec::new();
+        let mut replaced = false;
+        while is_cons(alist, cells) {
+            let item = car!(alist, cells);
+            if !replaced && is_cons(item, cells) && is_equal(key, car!(item, cells), cells) {
+                pairs.push(pair);
+                replaced = true;
+            } else {
+                pairs.push(item);
+            }
+            alist = cdr!(alist, cells);
+        }
+        if !replaced {
+            pairs.insert(0, pair);
+        }
+        Ok(list_of(&pairs, cells))
+    }
+}
+
+/// `memq` returns the rest of the list starting at the first element that
+/// is `is_eq` to the key, `assq` the first pair whose head is. Both give
+/// `()` when nothing matches.
+fn eval_memq_assq(op: SymbolIndex,
+                  exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (key, list) = split_binary(exp, cells);
+        let key = eval(key, cells, env, ns)?;
+        let mut list = eval(list, cells, env, ns)?;
+        while is_cons(list, cells) {
+            let item = car!(list, cells);
+            if op == ns.memq && is_eq(key, item, cells) {
+                return Ok(list);
+            }
+            if op == ns.assq && is_cons(item, cells) && is_eq(key, car!(item, cells), cells) {
+                return Ok(item);
+            }
+            list = cdr!(list, cells);
+        }
+        Ok(NIL_INDEX)
+    }
+}
+
+/// Reverses the list `exp` and each list nested in it, building new cells.
+/// A dotted tail isn't an element, so it's left out.
+fn deep_reverse(exp: CellIndex,
+                depth: usize,
+                cells: &mut CellStorage)
+                -> Result<CellIndex, EvalError> {
+    if depth > MAX_DEPTH {
+        return Err(EvalError::TooDeep);
+    }
+    if !is_cons(exp, cells) {
+        return Ok(exp);
+    }
+    let mut reversed = NIL_INDEX;
+    let mut exp = exp;
+    while is_cons(exp, cells) {
+        let item = deep_reverse(car!(exp, cells), depth + 1, cells)?;
+        reversed = cons(item, reversed, cells);
+        exp = cdr!(exp, cells);
+    }
+    Ok(reversed)
+}
+
+fn eval_deep_reverse(exp: CellIndex,
+                     cells: &mut CellStorage,
+                     env: &mut Env,
+                     ns: &DefaultNS)
+                     -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        deep_reverse(arg, 0, cells)
+    }
+}
+
+/// An atom has depth 0 and a list one more than its deepest element.
+fn max_depth(exp: CellIndex, depth: usize, cells: &CellStorage) -> Result<i32, EvalError> {
+    if depth > MAX_DEPTH {
+        return Err(EvalError::TooDeep);
+    }
+    if !is_cons(exp, cells) {
+        return Ok(0);
+    }
+    let mut deepest = 0;
+    let mut exp = exp;
+    while is_cons(exp, cells) {
+        deepest = cmp::max(deepest, max_depth(car!(exp, cells), depth + 1, cells)?);
+        exp = cdr!(exp, cells);
+    }
+    Ok(deepest + 1)
+}
+
+fn eval_max_depth(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let depth = max_depth(arg, 0, cells)?;
+        Ok(cells.alloc_cell(CellType::Number(depth)))
+    }
+}
+
+/// Pushes the atoms of `exp` onto `atoms` from left to right, skipping
+/// empty lists.
+fn flatten_into(exp: CellIndex,
+                depth: usize,
+                atoms: &mut Vec<CellIndex>,
+                cells: &CellStorage)
+                -> Result<(), EvalError> {
+    if depth > MAX_DEPTH {
+        return Err(EvalError::TooDeep);
+    }
+    let mut exp = exp;
+    while is_cons(exp, cells) {
+        flatten_into(car!(exp, cells), depth + 1, atoms, cells)?;
+        exp = cdr!(exp, cells);
+    }
+    if exp != NIL_INDEX {
+        atoms.push(exp);
+    }
+    Ok(())
+}
+
+fn eval_flatten(exp: CellIndex,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let arg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        let mut atoms = Vec::new();
+        flatten_into(arg, 0, &mut atoms, cells)?;
+        Ok(list_of(&atoms, cells))
+    }
+}
+
+fn eval_error(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let msg = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+        Err(EvalError::UserError(exp_to_string(msg, cells, env)))
+    }
+}
+
+fn eval_exit(exp: CellIndex,
+             cells: &mut CellStorage,
+             env: &mut Env,
+             ns: &DefaultNS)
+             -> Result<CellIndex, EvalError> {
+    if !env.capabilities.exit {
+        return Err(EvalError::PermissionDenied(ns.exit));
+    }
+    let code = if cdr!(exp, cells) == NIL_INDEX {
+        0
+    } else if is_unary(exp, cells) {
+        eval_number(car!(cdr!(exp, cells), cells), cells, env, ns)?
+    } else {
+        return Err(EvalError::NonUnary);
+    };
+    if env.exit_process {
+        // The REPL never gets to write out what was printed.
+        print!("{}", env.take_printed());
+        let _ = io::stdout().flush();
+        process::exit(code);
+    }
+    Err(EvalError::Exit(code))
+}
+
+fn eval_assert(exp: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+    if !is_unary(exp, cells) {
+        Err(EvalError::NonUnary)
+    } else {
+        let expr = car!(cdr!(exp, cells), cells);
+        let res = eval(expr, cells, env, ns)?;
+        if is_truthy(res, cells, env) {
+            Ok(res)
+        } else {
+            Err(EvalError::AssertionFailed(exp_to_string(expr, cells, env)))
+        }
+    }
+}
+
+fn eval_define_test(exp: CellIndex,
+                    cells: &mut CellStorage,
+                    env: &mut Env)
+                    -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        return Err(EvalError::NonBinary);
+    }
+    let (name, body) = split_binary(exp, cells);
+    let name = match cells.val_of(name) {
+        CellType::Symbol(sym) => sym,
+        _ => return Err(EvalError::NotSymbol(name)),
+    };
+    let body = copy_tree(body, 0, cells)?;
+    env.define_test(name, body);
+    Ok(cells.alloc_cell(CellType::Symbol(name)))
+}
+
+/// Runs every registered test, counting it as failed if it raises an
+/// error (such as a failed `assert`). Gives `(passes failures (name ...))`
+/// with the names of the failed tests.
+fn eval_run_tests(exp: CellIndex,
+                  cells: &mut CellStorage,
+                  env: &mut Env,
+                  ns: &DefaultNS)
+                  -> Result<CellIndex, EvalError> {
+    if cdr!(exp, cells) != NIL_INDEX {
+        return Err(EvalError::ArgCount {
+            expected: 0,
+            got: count_args(exp, cells),
+        });
+    }
+    let tests = env.tests().to_vec();
+    let mut passes = 0;
+    let mut failed = Vec::new();
+    for (name, body) in tests {
+        match eval(body, cells, env, ns) {
+            Ok(_) => passes += 1,
+            Err(EvalError::Exit(code)) => return Err(EvalError::Exit(code)),
+            Err(_) => failed.push(cells.alloc_cell(CellType::Symbol(name))),
+        }
+    }
+    let summary = [cells.alloc_cell(CellType::Number(passes)),
+                   cells.alloc_cell(CellType::Number(failed.len() as i32)),
+                   list_of(&failed, cells)];
+    Ok(list_of(&summary, cells))
+}
+
+/// The text of an error as `catch` hands it over: what `error` was given,
+/// or the REPL's description without its trailing newline.
+fn error_message(err: EvalError, cells: &CellStorage, env: &Env) -> String {
+    match err {
+        EvalError::UserError(msg) => msg,
+        EvalError::At { cause, .. } => error_message(*cause, cells, env),
+        err => {
+            let mut text = Vec::new();
+            // Writing to a Vec can't fail.
+            describe_err(&mut text, err, cells, env).unwrap();
+            String::from_utf8_lossy(&text).trim_end().to_string()
+        }
+    }
+}
+
+/// Evaluates the body, and if it raises an error, gives back the cells the
+/// body allocated and calls the handler with the error's message as a
+/// symbol. Symbols are the only text there is, so each distinct message is
+/// interned once and reused after that. `exit` isn't caught, so that it
+/// still ends the program.
+fn eval_catch(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    if !is_binary(exp, cells) {
+        Err(EvalError::NonBinary)
+    } else {
+        let (body, handler) = split_binary(exp, cells);
+        cells.start_tracking();
+        let res = eval(body, cells, env, ns);
+        let allocated = cells.stop_tracking();
+        match res {
+            Ok(res) => Ok(res),
+            Err(EvalError::Exit(code)) => Err(EvalError::Exit(code)),
+            Err(err) => {
+                let msg = error_message(err, cells, env);
+                reclaim(allocated, cells, env);
+                let handler = eval(handler, cells, env, ns)?;
+                let msg = env.add_sym(msg);
+                let msg = cells.alloc_cell(CellType::Symbol(msg));
+                apply(handler, &[msg], cells, env, ns)
+            }
+        }
+    }
+}
+
+/// Evaluates each form in turn, returning the value of the last one.
+fn eval_begin(exp: CellIndex,
+              cells: &mut CellStorage,
+              env: &mut Env,
+              ns: &DefaultNS)
+              -> Result<CellIndex, EvalError> {
+    let mut res = NIL_INDEX;
+    let mut forms = cdr!(exp, cells);
+    while is_cons(forms, cells) {
+        res = eval(car!(forms, cells), cells, env, ns)?;
+        forms = cdr!(forms, cells);
+    }
+    Ok(res)
+}
+
+pub fn eval(exp: CellIndex,
+            cells: &mut CellStorage,
+            env: &mut Env,
+            ns: &DefaultNS)
+            -> Result<CellIndex, EvalError> {
+    env.steps += 1;
+    if env.step_limit.is_some_and(|limit| env.steps > limit) {
+        return Err(EvalError::StepLimit);
+    }
+    eval_exp(exp, cells, env, ns).map_err(|err| at(err, exp, cells, env))
+}
+
+fn eval_exp(exp: CellIndex,
+            cells: &mut CellStorage,
+            env: &mut Env,
+            ns: &DefaultNS)
+            -> Result<CellIndex, EvalError> {
+    let cell = cells.get(exp);
+    if let CellType::Symbol(sym) = cell.val {
+        if env.strict && !is_bound(sym, env, ns) {
+            Err(EvalError::UnboundSymbol(sym))
+        } else {
+            Ok(exp)
+        }
+    } else if is_atom(exp, cells) {
+        Ok(exp)
+    } else if let Some(val) = env.get_folded(exp) {
+        Ok(cells.alloc_cell(CellType::Number(val)))
+    } else if let CellType::Cons(head) = cell.val {
+        if let CellType::Symbol(op) = cells.val_of(head) {
+            if op == ns.quote {
+                if !is_unary(exp, cells) {
+                    Err(EvalError::NonUnary)
+                } else {
+                    Ok(car!(cdr!(exp, cells), cells))
+                }
+            } else if op == ns.car_safe || op == ns.cdr_safe {
+                if !is_unary(exp, cells) {
+                    Err(EvalError::NonUnary)
+                } else {
+                    let res = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+                    if !is_cons(res, cells) {
+                        Ok(NIL_INDEX)
+                    } else if op == ns.car_safe {
+                        Ok(car!(res, cells))
+                    } else {
+                        Ok(cdr!(res, cells))
+                    }
+                }
+            } else if op == ns.hd || op == ns.tl {
+                if !is_unary(exp, cells) {
+                    Err(EvalError::NonUnary)
+                } else {
+                    let res = eval(car!(cdr!(exp, cells), cells), cells, env, ns)?;
+                    if !is_cons(res, cells) {
+                        Err(EvalError::NotCons(exp))
+                    } else if op == ns.hd {
+                        Ok(car!(res, cells))
+                    } else {
+                        Ok(cdr!(res, cells))
+                    }
+                }
+            } else if op == ns.cons {
+                eval_cons(exp, cells, env, ns)
+            } else if op == ns.count_cells {
+                eval_count_cells(exp, cells, env, ns)
+            } else if op == ns.make_list {
+                eval_make_list(exp, cells, env, ns)
+            } else if op == ns.map2 {
+                eval_map2(exp, cells, env, ns)
+            } else if op == ns.copy || op == ns.deep_copy {
+                eval_copy(exp, cells, env, ns)
+            } else if op == ns.list_copy {
+                eval_list_copy(exp, cells, env, ns)
+            } else if op == ns.draw {
+                eval_draw(exp, cells, env, ns)
+            } else if is_arithmetic(op, ns) {
+                eval_arithmetic(op, exp, cells, env, ns)
+            } else if is_numeric_predicate(op, ns) {
+                eval_numeric_predicate(op, exp, cells, env, ns)
+            } else if op == ns.set_seed {
+                eval_set_seed(exp, cells, env, ns)
+            } else if op == ns.cells_used || op == ns.cells_free {
+                eval_cell_counts(op, exp, cells, ns)
+            } else if op == ns.time_ms {
+                eval_time_ms(exp, cells, env, ns)
+            } else if op == ns.random {
+                eval_random(exp, cells, env, ns)
+            } else if is_bitwise(op, ns) {
+                eval_bitwise(op, exp, cells, env, ns)
+            } else if op == ns.bit_not {
+                eval_bit_not(exp, cells, env, ns)
+            } else if op == ns.gcd || op == ns.lcm {
+                eval_gcd_lcm(op, exp, cells, env, ns)
+            } else if op == ns.lt {
+                eval_lt(exp, cells, env, ns)
+            } else if op == ns.pp {
+                eval_pp(exp, cells, env, ns)
+            } else if op == ns.sort {
+                eval_sort(exp, cells, env, ns)
+            } else if op == ns.take || op == ns.drop {
+                eval_take_drop(op, exp, cells, env, ns)
+            } else if op == ns.list_tabulate {
+                eval_list_tabulate(exp, cells, env, ns)
+            } else if op == ns.take_while || op == ns.drop_while {
+                eval_take_drop_while(op, exp, cells, env, ns)
+            } else if op == ns.partition {
+                eval_partition(exp, cells, env, ns)
+            } else if op == ns.count {
+                eval_count_if(exp, cells, env, ns)
+            } else if op == ns.any || op == ns.every {
+                eval_any_every(op, exp, cells, env, ns)
+            } else if op == ns.remove || op == ns.remq {
+                eval_remove(op, exp, cells, env, ns)
+            } else if op == ns.assoc_set {
+                eval_assoc_set(exp, cells, env, ns)
+            } else if op == ns.unfold {
+                eval_unfold(exp, cells, env, ns)
+            } else if op == ns.compile {
+                eval_compile(exp, cells, env, ns)
+            } else if op == ns.range {
+                eval_range(exp, cells, env, ns)
+            } else if op == ns.fold_right {
+                eval_fold_right(exp, cells, env, ns)
+            } else if op == ns.reduce {
+                eval_reduce(exp, cells, env, ns)
+            } else if op == ns.last || op == ns.last_pair {
+                eval_last(op, exp, cells, env, ns)
+            } else if op == ns.vector || op == ns.list_to_vector {
+                eval_vector(op, exp, cells, env, ns)
+            } else if op == ns.vector_ref {
+                eval_vector_ref(exp, cells, env, ns)
+            } else if op == ns.vector_set {
+                eval_vector_set(exp, cells, env, ns)
+            } else if op == ns.vector_length {
+                eval_vector_length(exp, cells, env, ns)
+            } else if op == ns.make_hash || op == ns.hash_set || op == ns.hash_ref ||
+                      op == ns.hash_remove {
+                eval_hash(op, exp, cells, env, ns)
+            } else if op == ns.defmacro {
+                eval_defmacro(exp, cells, env)
+            } else if op == ns.delay {
+                eval_delay(exp, cells, env)
+            } else if op == ns.force {
+                eval_force(exp, cells, env, ns)
+            } else if op == ns.macroexpand {
+                eval_macroexpand(exp, cells, env, ns)
+            } else if op == ns.bound {
+                eval_bound(exp, cells, env, ns)
+            } else if op == ns.symbols {
+                eval_symbols(exp, cells, env)
+            } else if op == ns.help {
+                eval_help(exp, cells, env, ns)
+            } else if op == ns.memq || op == ns.assq {
+                eval_memq_assq(op, exp, cells, env, ns)
+            } else if op == ns.deep_reverse {
+                eval_deep_reverse(exp, cells, env, ns)
+            } else if op == ns.max_depth {
+                eval_max_depth(exp, cells, env, ns)
+            } else if op == ns.flatten {
+                eval_flatten(exp, cells, env, ns)
+            } else if op == ns.exit {
+                eval_exit(exp, cells, env, ns)
+            } else if op == ns.define_test {
+                eval_define_test(exp, cells, env)
+            } else if op == ns.run_tests {
+                eval_run_tests(exp, cells, env, ns)
+            } else if op == ns.assert {
+                eval_assert(exp, cells, env, ns)
+            } else if op == ns.error {
+                eval_error(exp, cells, env, ns)
+            } else if op == ns.catch {
+                eval_catch(exp, cells, env, ns)
+            } else if op == ns.begin || op == ns.progn {
+                eval_begin(exp, cells, env, ns)
+            } else if env.get_macro(op).is_some() {
+                // Builtins take precedence over macros of the same name.
+                let expansion = expand_macro(op, exp, cells, env)?;
+                eval(expansion, cells, env, ns)
+            } else {
+                Err(EvalError::UnknownOperator(op))
+            }
+        } else {
+            Err(EvalError::IllegalOperator)
+        }
+    } else {
+        panic!("Invalid expression")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cell::{Cell, init_storage};
+    use env::Capabilities;
+    use parser::{Parser, SpanTable};
+
+    fn eval_str(input: &str,
+                cells: &mut CellStorage,
+                env: &mut Env,
+                ns: &DefaultNS)
+                -> Result<CellIndex, EvalError> {
+        let buf = input.as_bytes().to_vec();
+        let (exp, _) = Parser::new(env).parse(&buf, cells).unwrap();
+        eval(exp, cells, env, ns)
+    }
+
+    /// Evaluates `input` with its first argument replaced by the already
+    /// built `target`, standing in for the variables this evaluator lacks.
+    fn eval_on(input: &str,
+               target: CellIndex,
+               cells: &mut CellStorage,
+               env: &mut Env,
+               ns: &DefaultNS)
+               -> Result<CellIndex, EvalError> {
+        let buf = input.as_bytes().to_vec();
+        let (exp, _) = Parser::new(env).parse(&buf, cells).unwrap();
+        let args = cdr!(exp, cells);
+        cells.set_val(args, CellType::Cons(target));
+        eval(exp, cells, env, ns)
+    }
+
+    /// Points every use of the symbol `name` in `exp` at `target` instead.
+    fn patch_symbol(exp: CellIndex,
+                    name: &str,
+                    target: CellIndex,
+                    cells: &mut CellStorage,
+                    env: &mut Env) {
+        let sym = env.add_sym(name.to_string());
+        let mut pending = vec![exp];
+        while let Some(idx) = pending.pop() {
+            if let CellType::Cons(head) = cells.val_of(idx) {
+                if let CellType::Symbol(s) = cells.val_of(head) {
+                    if s == sym {
+                        cells.set_val(idx, CellType::Cons(target));
+                    }
+                }
+                pending.push(head);
+                pending.push(cells.tail_of(idx));
+            }
+        }
+    }
+
+    fn assert_number(idx: CellIndex, expected: i32, cells: &CellStorage) {
+        match cells.val_of(idx) {
+            CellType::Number(n) => assert_eq!(n, expected),
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn count_cells_flat_list() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(count-cells ('(1 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+    }
+
+    #[test]
+    fn count_cells_shared_tail() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+
+        let three = cells.alloc_cell(CellType::Number(3));
+        let tail = cons(three, NIL_INDEX, &mut cells);
+        let one = cells.alloc_cell(CellType::Number(1));
+        let first = cons(one, tail, &mut cells);
+        let two = cells.alloc_cell(CellType::Number(2));
+        let second = cons(two, tail, &mut cells);
+        let rest = cons(second, NIL_INDEX, &mut cells);
+        let both = cons(first, rest, &mut cells);
+
+        assert_eq!(count_cells(first, &cells), 2);
+        assert_eq!(count_cells(second, &cells), 2);
+        // Two spine cells, plus one head cell per list, plus the shared tail.
+        assert_eq!(count_cells(both, &cells), 5);
+    }
+
+    #[test]
+    fn unbound_symbol_lenient() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("foo", &mut cells, &mut env, &ns).unwrap();
+        match cells.val_of(res) {
+            CellType::Symbol(sym) => assert_eq!(env.get_sym(sym), "foo"),
+            other => panic!("expected a symbol, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unbound_symbol_strict() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.strict = true;
+
+        match eval_str("foo", &mut cells, &mut env, &ns) {
+            Err(EvalError::UnboundSymbol(sym)) => assert_eq!(env.get_sym(sym), "foo"),
+            other => panic!("expected an unbound symbol error, got {:?}", other),
+        }
+        // Builtins, macros and quoted symbols are still fine.
+        assert!(eval_str("add", &mut cells, &mut env, &ns).is_ok());
+        assert!(eval_str("('foo)", &mut cells, &mut env, &ns).is_ok());
+        eval_str("(defmacro foo (x) x)", &mut cells, &mut env, &ns).unwrap();
+        assert!(eval_str("foo", &mut cells, &mut env, &ns).is_ok());
+    }
+
+    #[test]
+    fn folded_results_match() {
+        let inputs = ["(add 1 2)",
+                      "(mul (add 1 2) (sub 10 4))",
+                      "(div (mod 17 5) (add 1 ('2)))",
+                      "(hd (cons (add 2 3) (sub 2 3)))",
+                      "(add 1 ('x))"];
+        for input in inputs.iter() {
+            let mut buf = [Cell::empty(); 64];
+            let mut cells = init_storage(&mut buf);
+            let mut env = Env::new();
+            let ns = DefaultNS::new(&mut env);
+
+            let unfolded = eval_str(input, &mut cells, &mut env, &ns);
+            let unfolded = unfolded.map(|idx| cells.val_of(idx));
+
+            let buf = input.as_bytes().to_vec();
+            let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+            fold_constants(exp, &cells, &mut env, &ns);
+            let folded = eval(exp, &mut cells, &mut env, &ns).map(|idx| cells.val_of(idx));
+
+            assert_eq!(format!("{:?}", unfolded), format!("{:?}", folded));
+        }
+    }
+
+    #[test]
+    fn folding_leaves_unevaluated_operands_alone() {
+        let cases: [&[&str]; 7] = [&["(defmacro q (x) (' x))", "(q (add 1 2))"],
+                                   &["(compile (add 1 2))"],
+                                   &["(force (delay (add 1 2)))"],
+                                   &["(defmacro q (x) (' x))", "(macroexpand (q (add 1 2)))"],
+                                   &["(defmacro r (x) (add 1 2))", "(macroexpand (r 5))"],
+                                   &["(define-test three (add 1 2))", "(run-tests)"],
+                                   &["(assert (lt 3 (add 1 1)))"]];
+        for forms in cases.iter() {
+            let mut outputs = Vec::new();
+            for &fold in [false, true].iter() {
+                let mut buf = [Cell::empty(); 128];
+                let mut cells = init_storage(&mut buf);
+                let mut env = Env::new();
+                let ns = DefaultNS::new(&mut env);
+                let mut output = String::new();
+                for form in forms.iter() {
+                    let buf = form.as_bytes().to_vec();
+                    let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+                    if fold {
+                        fold_constants(exp, &cells, &mut env, &ns);
+                    }
+                    output = match eval(exp, &mut cells, &mut env, &ns) {
+                        Ok(res) => exp_to_string(res, &cells, &env),
+                        Err(err) => format!("{:?}", err),
+                    };
+                }
+                outputs.push(output);
+            }
+            assert_eq!(outputs[0], outputs[1], "{:?}", forms);
+        }
+    }
+
+    #[test]
+    fn folding_keeps_error_messages() {
+        let mut outputs = Vec::new();
+        for &fold in [false, true].iter() {
+            let mut buf = [Cell::empty(); 256];
+            let mut cells = init_storage(&mut buf);
+            let mut env = Env::new();
+            let ns = DefaultNS::new(&mut env);
+            env.error_context = true;
+            let mut output = Vec::new();
+            for form in ["(defmacro id (x) x)", "(catch (hd (add 1 2)) id)", "(hd (add 1 2))"]
+                .iter() {
+                let buf = form.as_bytes().to_vec();
+                let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+                if fold {
+                    fold_constants(exp, &cells, &mut env, &ns);
+                }
+                match eval(exp, &mut cells, &mut env, &ns) {
+                    Ok(res) => writeln!(output, "{}", exp_to_string(res, &cells, &env)).unwrap(),
+                    Err(err) => describe_err(&mut output, err, &cells, &env).unwrap(),
+                }
+            }
+            outputs.push(String::from_utf8(output).unwrap());
+        }
+        assert!(outputs[0].contains("(hd (add 1 2)) does not evaluate to a cons pair!"),
+                "{}",
+                outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn fold_bails_out_on_non_constants() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, folds) in [("(mul (add 1 2) 3)", true),
+                                ("(add 1 ('2))", false),
+                                ("('(add 1 2))", false),
+                                ("(div 1 0)", false)]
+            .iter() {
+            let buf = input.as_bytes().to_vec();
+            let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+            let folded = fold_constants(exp, &cells, &mut env, &ns);
+            assert_eq!(folded.is_some(), folds, "{}", input);
+            cells.free_cell(exp, &mut env);
+        }
+    }
+
+    #[test]
+    fn error_context_names_operand() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.error_context = true;
+
+        match eval_str("(mul 2 (add 1 ('foo)))", &mut cells, &mut env, &ns) {
+            Err(EvalError::At { expr, cause, .. }) => {
+                assert_eq!(expr, "(' foo)");
+                match *cause {
+                    EvalError::NonNumeric => {}
+                    other => panic!("expected a non numeric error, got {:?}", other),
+                }
+            }
+            other => panic!("expected an error with context, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn make_list() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(make-list 3 0)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(0 0 0)");
+        let res = eval_str("(make-list 0 x)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        match eval_str("(make-list (sub 0 1) x)", &mut cells, &mut env, &ns) {
+            Err(EvalError::NegativeCount(-1)) => {}
+            other => panic!("expected a negative count error, got {:?}", other),
+        }
+        match eval_str("(make-list 100 0)", &mut cells, &mut env, &ns) {
+            Err(EvalError::OutOfCells) => {}
+            other => panic!("expected to run out of cells, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map2() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(map2 add ('(1 2 3)) ('(10 20 30)))",
+                           &mut cells,
+                           &mut env,
+                           &ns)
+            .unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(11 22 33)");
+        let res = eval_str("(map2 cons ('(1 2 3)) ('(10 20)))", &mut cells, &mut env, &ns)
+            .unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "((1 . 10) (2 . 20))");
+    }
+
+    #[test]
+    fn error_span_names_line() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.error_context = true;
+        env.spans = Some(SpanTable::new());
+
+        match eval_str("(add 1\n  ('foo))", &mut cells, &mut env, &ns) {
+            Err(EvalError::At { span, .. }) => {
+                assert_eq!(span, Some(Span { line: 2, col: 3 }));
+            }
+            other => panic!("expected an error with context, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spans_skip_interned_and_freed_cells() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        env.spans = Some(SpanTable::new());
+
+        let buf = b"(mul 7 1000)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let seven = car!(cdr!(exp, cells), cells);
+        let thousand = car!(cdr!(cdr!(exp, cells), cells), cells);
+        let spans = |idx, env: &Env| env.spans.as_ref().unwrap().get(idx);
+        assert_eq!(spans(seven, &env), None);
+        assert_eq!(spans(thousand, &env), Some(Span { line: 1, col: 8 }));
+
+        cells.free_cell(exp, &mut env);
+        assert_eq!(spans(exp, &env), None);
+        assert_eq!(spans(thousand, &env), None);
+    }
+
+    #[test]
+    fn copy_is_independent() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(copy ('(1 (2 3) . 4)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 (2 3) . 4)");
+
+        let orig = eval_str("('(1 (2 3)))", &mut cells, &mut env, &ns).unwrap();
+        let copy = deep_copy(orig, &mut cells);
+        assert!(copy != orig);
+        let nested = car!(cdr!(copy, cells), cells);
+        let five = cells.alloc_cell(CellType::Number(5));
+        cells.set_val(nested, CellType::Cons(five));
+        assert_eq!(exp_to_string(copy, &cells, &env), "(1 (5 3))");
+        assert_eq!(exp_to_string(orig, &cells, &env), "(1 (2 3))");
+    }
+
+    #[test]
+    fn numeric_predicates() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        // Expected results for 3, -3 and 0 respectively.
+        let cases = [("positive?", [true, false, false]),
+                     ("negative?", [false, true, false]),
+                     ("zero?", [false, false, true]),
+                     ("even?", [false, false, true]),
+                     ("odd?", [true, true, false])];
+        for &(pred, expected) in cases.iter() {
+            for (arg, &holds) in ["3", "(sub 0 3)", "0"].iter().zip(expected.iter()) {
+                let input = format!("({} {})", pred, arg);
+                let res = eval_str(&input, &mut cells, &mut env, &ns).unwrap();
+                let res = exp_to_string(res, &cells, &env);
+                assert_eq!(res, if holds { "t" } else { "()" }, "{}", input);
+            }
+        }
+        match eval_str("(even? ('x))", &mut cells, &mut env, &ns) {
+            Err(EvalError::NonNumeric) => {}
+            other => panic!("expected a non numeric error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gcd_and_lcm() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(gcd 12 18)", 6),
+                                   ("(lcm 4 6)", 12),
+                                   ("(gcd 0 0)", 0),
+                                   ("(gcd 12 18 (sub 0 8))", 2),
+                                   ("(lcm 2 3 4)", 12),
+                                   ("(lcm)", 1)]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, expected, &cells);
+        }
+        match eval_str("(lcm 65536 65537)", &mut cells, &mut env, &ns) {
+            Err(EvalError::Overflow) => {}
+            other => panic!("expected an overflow error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sort_with_comparator() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(sort ('(3 1 2)) lt)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2 3)");
+        let res = eval_str("(sort ('(5 2 8 2 1 9)) lt)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2 2 5 8 9)");
+        let res = eval_str("(sort () lt)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn take_and_drop() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(take 2 ('(1 2 3)))", "(1 2)"),
+                                   ("(take 3 ('(1 2 3)))", "(1 2 3)"),
+                                   ("(take 5 ('(1 2 3)))", "(1 2 3)"),
+                                   ("(drop 2 ('(1 2 3)))", "(3)"),
+                                   ("(drop 3 ('(1 2 3)))", "()"),
+                                   ("(drop 5 ('(1 2 3)))", "()")]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), expected, "{}", input);
+        }
+        match eval_str("(take (sub 0 1) ('(1 2 3)))", &mut cells, &mut env, &ns) {
+            Err(EvalError::NegativeCount(-1)) => {}
+            other => panic!("expected a negative count error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn range() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(range 1 4)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2 3)");
+        let res = eval_str("(range 3)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(0 1 2)");
+        let res = eval_str("(range 4 4)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        let res = eval_str("(range 5 2)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        match eval_str("(range 100)", &mut cells, &mut env, &ns) {
+            Err(EvalError::OutOfCells) => {}
+            other => panic!("expected to run out of cells, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn take_while_and_drop_while() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(take-while even? ('(2 4 5 6)))", "(2 4)"),
+                                   ("(drop-while even? ('(2 4 5 6)))", "(5 6)"),
+                                   ("(take-while positive? ('(1 2 3)))", "(1 2 3)"),
+                                   ("(drop-while positive? ('(1 2 3)))", "()"),
+                                   ("(take-while zero? ('(1 2 3)))", "()"),
+                                   ("(drop-while zero? ('(1 2 3)))", "(1 2 3)")]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn partition() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(partition even? ('(1 2 3 4)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "((2 4) (1 3))");
+        let res = eval_str("(partition even? ('()))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(() ())");
+    }
+
+    #[test]
+    fn count() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(count even? ('(1 2 3 4 5 6)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(count even? ('()))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 0, &cells);
+    }
+
+    #[test]
+    fn any_and_every() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        // The predicates fail on the symbol, so these only succeed if the walk
+        // stops before reaching it.
+        for &(input, expected) in [("(any even? ('(1 2 x)))", "t"),
+                                   ("(every odd? ('(1 2 x)))", "()"),
+                                   ("(any even? ('(1 3)))", "()"),
+                                   ("(every odd? ('(1 3)))", "t"),
+                                   ("(any even? ('()))", "()"),
+                                   ("(every even? ('()))", "t")]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), expected, "{}", input);
+        }
+        assert!(eval_str("(any odd? ('(2 x)))", &mut cells, &mut env, &ns).is_err());
+    }
+
+    #[test]
+    fn unfold() {
+        let mut buf = [Cell::empty(); 512];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        // Macros stand in for the lambdas this evaluator lacks.
+        for def in ["(defmacro inc (x) (add x 1))",
+                    "(defmacro id (x) x)",
+                    "(defmacro past-4? (x) (lt 4 x))"]
+            .iter() {
+            eval_str(def, &mut cells, &mut env, &ns).unwrap();
+        }
+        let res = eval_str("(unfold past-4? id 0 inc)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(0 1 2 3 4)");
+        let res = eval_str("(unfold past-4? even? 1 inc)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(() t () t)");
+        let res = eval_str("(unfold positive? id 1 inc)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        match eval_str("(unfold positive? id 1)", &mut cells, &mut env, &ns) {
+            Err(EvalError::ArgCount { expected: 4, got: 3 }) => {}
+            other => panic!("expected an argument count error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compile_lists_bytecode() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(compile (add 1 (mul 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env),
+                   "((push 1) (push 2) (push 3) (mul) (add))");
+        match eval_str("(compile (cons 1 2))", &mut cells, &mut env, &ns) {
+            Err(EvalError::NotCompilable(_)) => {}
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn list_tabulate() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(list-tabulate 4 bit-not)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(-1 -2 -3 -4)");
+        let res = eval_str("(list-tabulate 3 even?)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(t () t)");
+        let res = eval_str("(list-tabulate 0 even?)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        match eval_str("(list-tabulate (sub 0 1) even?)", &mut cells, &mut env, &ns) {
+            Err(EvalError::NegativeCount(-1)) => {}
+            other => panic!("expected a negative count error, got {:?}", other),
+        }        match eval_str("(list-tabulate 300 bit-not)", &mut cells, &mut env, &ns) {
+            Err(EvalError::OutOfCells) => {}
+            other => panic!("expected to run out of cells, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fold_right() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(fold-right cons () ('(1 2 3)))", &mut cells, &mut env, &ns)
+            .unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2 3)");
+        let res = eval_str("(fold-right sub 0 ('(1 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 2, &cells);
+    }
+
+    #[test]
+    fn catch_user_error() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        eval_str("(defmacro id (msg) msg)", &mut cells, &mut env, &ns).unwrap();
+        eval_str("(defmacro recover (msg) ('recovered))", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(catch (add 1 (error ('oops))) recover)", &mut cells, &mut env, &ns);
+        assert_eq!(exp_to_string(res.unwrap(), &cells, &env), "recovered");
+        let res = eval_str("(catch (add 1 2) recover)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+
+        // The handler gets the message.
+        let res = eval_str("(catch (add 1 (error ('oops))) id)", &mut cells, &mut env, &ns);
+        assert_eq!(exp_to_string(res.unwrap(), &cells, &env), "oops");
+        let res = eval_str("(catch (hd 5) id)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(hd 5) does not evaluate to a cons pair!");
+        env.error_context = true;
+        let res = eval_str("(catch (hd 5) id)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(hd 5) does not evaluate to a cons pair!");
+    }
+
+    #[test]
+    fn catch_gives_back_what_the_body_allocated() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        eval_str("(defmacro id (msg) msg)", &mut cells, &mut env, &ns).unwrap();
+
+        let buf = b"(catch (add 1 (make-list 20 1000)) id)".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let (free, symbols) = (cells.free_count(), env.symbol_count());
+        for _ in 0..20 {
+            let res = eval(form, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), "non numeric expression!");
+            cells.free_cell(res, &mut env);
+            assert_eq!(cells.free_count(), free);
+        }
+        // The message was interned once.
+        assert_eq!(env.symbol_count(), symbols + 1);
+
+        // What the body stored into a table made beforehand stays.
+        let table = eval_str("(make-hash)", &mut cells, &mut env, &ns).unwrap();
+        let buf = b"(catch (begin (hash-set! h 1 (cons 1000 2000)) (hd 5)) id)".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        patch_symbol(form, "h", table, &mut cells, &mut env);
+        assert!(eval(form, &mut cells, &mut env, &ns).is_ok());
+        eval_str("(make-list 5 3000)", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_on("(hash-ref h 1 ())", table, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1000 . 2000)");
+    }
+
+    #[test]
+    fn uncaught_user_error() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        match eval_str("(add 1 (error ('(bad input))))", &mut cells, &mut env, &ns) {
+            Err(EvalError::UserError(msg)) => assert_eq!(msg, "(bad input)"),
+            other => panic!("expected a user error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn progn_matches_begin() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(begin 1 2 3)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(progn 1 2 3)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(progn)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn reduce() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(reduce add ('(1 2 3 4)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 10, &cells);
+        let res = eval_str("(reduce sub ('(10 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 5, &cells);
+        match eval_str("(reduce add ())", &mut cells, &mut env, &ns) {
+            Err(EvalError::NotCons(_)) => {}
+            other => panic!("expected a not cons error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn last_and_last_pair() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(last ('(1 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(last-pair ('(1 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(3)");
+        let res = eval_str("(last ('(1)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 1, &cells);
+        let res = eval_str("(last-pair ('(1 . 2)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 . 2)");
+        match eval_str("(last ())", &mut cells, &mut env, &ns) {
+            Err(EvalError::NotCons(_)) => {}
+            other => panic!("expected a not cons error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn vectors() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(vector 1 (add 1 1) ('x))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "#(1 2 x)");
+        let res = eval_str("(list->vector ('(a (b) c)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "#(a (b) c)");
+        let res = eval_str("(vector)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "#()");
+
+        let res = eval_str("(vector-ref (vector 4 5 6) 2)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 6, &cells);
+        let res = eval_str("(vector-length (list->vector (range 7)))",
+                           &mut cells,
+                           &mut env,
+                           &ns)
+            .unwrap();
+        assert_number(res, 7, &cells);
+
+        for input in ["(vector-ref (vector 4 5 6) 3)", "(vector-ref (vector 4) (sub 0 1))"].iter() {
+            match eval_str(input, &mut cells, &mut env, &ns) {
+                Err(EvalError::IndexOutOfRange(_)) => {}
+                other => panic!("expected an index error, got {:?}", other),
+            }
+        }
+        match eval_str("(vector-length ('(1 2)))", &mut cells, &mut env, &ns) {
+            Err(EvalError::NotVector(_)) => {}
+            other => panic!("expected a not-vector error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn vector_set_updates_in_place() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(vector-set! (vector 0) 0 ('y))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "y");
+
+        let vec = eval_str("(vector 1 2 3)", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_on("(vector-set! v 1 (add 4 5))", vec, &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 9, &cells);
+        assert_eq!(exp_to_string(vec, &cells, &env), "#(1 9 3)");
+
+        match eval_on("(vector-set! v 3 0)", vec, &mut cells, &mut env, &ns) {
+            Err(EvalError::IndexOutOfRange(3)) => {}
+            other => panic!("expected an index error, got {:?}", other),
+        }
+        assert_eq!(exp_to_string(vec, &cells, &env), "#(1 9 3)");
+    }
+
+    #[test]
+    fn hash_tables() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let table = eval_str("(make-hash)", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_on("(hash-set! h ('a) 1)", table, &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 1, &cells);
+        eval_on("(hash-set! h 2 ('(x y)))", table, &mut cells, &mut env, &ns).unwrap();
+        eval_on("(hash-set! h ('a) 3)", table, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(table, &cells, &env), "#<hash-table 2>");
+
+        let res = eval_on("(hash-ref h ('a) 0)", table, &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_on("(hash-ref h (add 1 1) 0)", table, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(x y)");
+        let res = eval_on("(hash-ref h ('b) (sub 0 1))", table, &mut cells, &mut env, &ns)
+            .unwrap();
+        assert_number(res, -1, &cells);
+
+        let res = eval_on("(hash-remove! h ('a))", table, &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_on("(hash-ref h ('a) 0)", table, &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 0, &cells);
+        let res = eval_on("(hash-remove! h ('a))", table, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+
+        match eval_on("(hash-ref h ('(1)) 0)", table, &mut cells, &mut env, &ns) {
+            Err(EvalError::Unhashable(_)) => {}
+            other => panic!("expected an unhashable key error, got {:?}", other),
+        }
+        match eval_str("(make-hash 1)", &mut cells, &mut env, &ns) {
+            Err(EvalError::ArgCount { expected: 0, got: 1 }) => {}
+            other => panic!("expected an argument count error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn macros_substitute_unevaluated_arguments() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(defmacro swap (a b) (cons b a))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "swap");
+        let res = eval_str("(swap 1 (add 1 1))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(2 . 1)");
+
+        eval_str("(defmacro both (x y) (' (x y)))", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(both (add 1 2) z)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "((add 1 2) z)");
+
+        match eval_str("(swap 1)", &mut cells, &mut env, &ns) {
+            Err(EvalError::ArgCount { expected: 2, got: 1 }) => {}
+            other => panic!("expected an argument count error, got {:?}", other),
+        }
+        match eval_str("(defmacro bad (1) 2)", &mut cells, &mut env, &ns) {
+            Err(EvalError::BadMacro(_)) => {}
+            other => panic!("expected a bad macro error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn symbols_lists_the_symbol_table() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(symbols)", &mut cells, &mut env, &ns).unwrap();
+        let mut names = Vec::new();
+        let mut list = res;
+        while is_cons(list, &cells) {
+            names.push(exp_to_string(car!(list, cells), &cells, &env));
+            list = cdr!(list, &cells);
+        }
+        for name in ["add", "cons", "hd", "symbols"].iter() {
+            assert!(names.iter().any(|n| n == name), "{} missing", name);
+        }
+        assert_eq!(names.len(), env.symbol_count());
+        match eval_str("(symbols 1)", &mut cells, &mut env, &ns) {
+            Err(EvalError::ArgCount { expected: 0, got: 1 }) => {}
+            other => panic!("expected an argument count error, got {:?}", other),
+        }
+
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        match eval_str("(symbols)", &mut cells, &mut env, &ns) {
+            Err(EvalError::OutOfCells) => {}
+            other => panic!("expected running out of cells, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bound_checks_builtins_and_macros() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        env.strict = true;
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(bound? add)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "t");
+        let res = eval_str("(bound? swap)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        eval_str("(defmacro swap (a b) (cons b a))", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(bound? swap)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "t");
+        let res = eval_str("(bound? 1)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn car_safe_and_cdr_safe() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(car-safe ('(1 2)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 1, &cells);
+        let res = eval_str("(cdr-safe ('(1 2)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(2)");
+        for input in ["(car-safe 5)", "(cdr-safe ('x))", "(car-safe ())", "(cdr-safe ())"].iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(res, NIL_INDEX, "{}", input);
+        }
+    }
+
+    #[test]
+    fn macroexpand_shows_the_expansion() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        eval_str("(defmacro swap (a b) (cons b (' a)))", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(macroexpand ('(swap (add 1 2) x)))", &mut cells, &mut env, &ns)
+            .unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(cons x (' (add 1 2)))");
+        let res = eval_str("(macroexpand ('(add 1 2)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(add 1 2)");
+        let res = eval_str("(macroexpand 5)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 5, &cells);
+    }
+
+    #[test]
+    fn force_evaluates_a_promise_once() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(force (delay (add 1 2)))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(delay (add 1 2))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "#<promise>");
+        let res = eval_str("(force 4)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 4, &cells);
+
+        // The body bumps a counter kept in a vector each time it runs.
+        let counter = eval_str("(vector 0)", &mut cells, &mut env, &ns).unwrap();
+        let buf = b"(delay (vector-set! v 0 (add 1 (vector-ref v 0))))".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        patch_symbol(form, "v", counter, &mut cells, &mut env);
+        let promise = eval(form, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(counter, &cells, &env), "#(0)");
+        for _ in 0..3 {
+            let res = eval_on("(force p)", promise, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, 1, &cells);
+        }
+        assert_eq!(exp_to_string(counter, &cells, &env), "#(1)");
+    }
+
+    #[test]
+    fn help_describes_builtins() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let text = ns.help(ns.add).unwrap();
+        assert!(text.contains("arithmetic"));
+        assert!(text.contains("two arguments"));
+
+        let res = eval_str("(help add)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "t");
+        assert_eq!(env.take_printed(), format!("{}\n", text));
+        let symbols = env.symbol_count();
+        let res = eval_str("(help frobnicate)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        // Only reading `frobnicate` added a symbol; the text didn't.
+        assert_eq!(env.symbol_count(), symbols + 1);
+    }
+
+    #[test]
+    fn memq_assq_compare_by_identity() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(memq ('c) ('(a b c d)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(c d)");
+        let res = eval_str("(memq 2 ('(1 2 3)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(2 3)");
+        let res = eval_str("(assq ('b) ('((a . 1) (b . 2))))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(b . 2)");
+
+        // An equal but separately built pair is a different object.
+        let res = eval_str("(memq ('(1)) ('((1) (2))))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+        let res = eval_str("(assq ('(1)) ('(((1) . a))))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn exit_raises_without_process_mode() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        env.error_context = true;
+        let ns = DefaultNS::new(&mut env);
+
+        match eval_str("(exit)", &mut cells, &mut env, &ns) {
+            Err(EvalError::Exit(0)) => {}
+            other => panic!("expected exit 0, got {:?}", other),
+        }
+        match eval_str("(begin 1 (exit (add 1 2)) 4)", &mut cells, &mut env, &ns) {
+            Err(EvalError::Exit(3)) => {}
+            other => panic!("expected exit 3, got {:?}", other),
+        }
+        // Catching doesn't stop an exit.
+        match eval_str("(catch (exit 1) bit-not)", &mut cells, &mut env, &ns) {
+            Err(EvalError::Exit(1)) => {}
+            other => panic!("expected exit 1, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn capabilities_gate_builtins() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.capabilities = Capabilities::none();
+
+        for &(input, denied) in [("(exit 1)", ns.exit),
+                                 ("(random 6)", ns.random),
+                                 ("(time-ms)", ns.time_ms)]
+            .iter() {
+            match eval_str(input, &mut cells, &mut env, &ns) {
+                Err(EvalError::PermissionDenied(sym)) => assert_eq!(sym, denied),
+                other => panic!("expected {} to be denied, got {:?}", input, other),
+            }
+        }
+        let res = eval_str("(set-seed! 4)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 4, &cells);
+
+        env.capabilities.random_and_clock = true;
+        assert!(eval_str("(random 6)", &mut cells, &mut env, &ns).is_ok());
+    }
+
+    #[test]
+    fn list_copy_shares_elements() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let buf = b"(list-copy ('(1 (2 3) 4)))".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let original = car!(cdr!(car!(cdr!(form, cells), cells), cells), cells);
+        let copy = eval(form, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(copy, &cells, &env), "(1 (2 3) 4)");
+        assert!(copy != original);
+
+        let nested = car!(cdr!(original, cells), cells);
+        assert_eq!(car!(cdr!(copy, cells), cells), nested);
+        cells.set_tail(cdr!(copy, cells), NIL_INDEX);
+        assert_eq!(exp_to_string(copy, &cells, &env), "(1 (2 3))");
+        assert_eq!(exp_to_string(original, &cells, &env), "(1 (2 3) 4)");
+
+        let res = eval_str("(list-copy 5)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 5, &cells);
+    }
+
+    #[test]
+    fn remove_and_remq() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        // Each quoted `(1)` is a separate list, so only `remove` sees them
+        // as the same.
+        for &(input, expected) in [("(remove 2 ('(1 2 3 2)))", "(1 3)"),
+                                   ("(remq 2 ('(1 2 3 2)))", "(1 3)"),
+                                   ("(remove 5 ('(1 2 3)))", "(1 2 3)"),
+                                   ("(remove ('(1)) ('(0 (1) 2 (1))))", "(0 2)"),
+                                   ("(remq ('(1)) ('(0 (1) 2 (1))))", "(0 (1) 2 (1))")]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn assoc_set() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let buf = b"(assoc-set ('((a . 1) (b . 2))) ('b) 3)".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let original = car!(cdr!(car!(cdr!(form, cells), cells), cells), cells);
+        let res = eval(form, &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "((a . 1) (b . 3))");
+        assert_eq!(exp_to_string(original, &cells, &env), "((a . 1) (b . 2))");
+
+        let res = eval_str("(assoc-set ('((a . 1))) ('c) 4)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "((c . 4) (a . 1))");
+        let res = eval_str("(assoc-set ('(((1 2) . x))) ('(1 2)) y)", &mut cells, &mut env, &ns);
+        assert_eq!(exp_to_string(res.unwrap(), &cells, &env), "(((1 2) . y))");
+    }
+
+    #[test]
+    fn deep_reverse() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(deep-reverse ('(1 (2 3) 4)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(4 (3 2) 1)");
+        let res = eval_str("(deep-reverse ('((1 (2 3)) ())))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(() ((3 2) 1))");
+        let res = eval_str("(deep-reverse 5)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 5, &cells);
+        let res = eval_str("(deep-reverse ())", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(max-depth ('(1 (2 (3)) 4)))", 3),
+                                   ("(max-depth ('(1 2 3)))", 1),
+                                   ("(max-depth ('((1 . 2))))", 2),
+                                   ("(max-depth 7)", 0),
+                                   ("(max-depth ())", 0)]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, expected, &cells);
+        }
+    }
+
+    #[test]
+    fn assert_checks_truthiness() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(assert (even? 4))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "t");
+        let res = eval_str("(assert (add 1 2))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        match eval_str("(assert (lt 3 (add 1 1)))", &mut cells, &mut env, &ns) {
+            Err(EvalError::AssertionFailed(expr)) => assert_eq!(expr, "(lt 3 (add 1 1))"),
+            other => panic!("expected a failed assertion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_tests_counts_passes_and_failures() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(run-tests)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(0 0 ())");
+        eval_str("(define-test adds (assert (zero? (sub 2 (add 1 1)))))",
+                 &mut cells,
+                 &mut env,
+                 &ns)
+            .unwrap();
+        eval_str("(define-test sorts (assert (lt 2 1)))", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(run-tests)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 1 (sorts))");
+    }
+
+    #[test]
+    fn flatten() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(flatten ('(1 (2 (3)) 4)))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2 3 4)");
+        let res = eval_str("(flatten ('(() (1 . 2))))", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(1 2)");
+        let res = eval_str("(flatten 5)", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(exp_to_string(res, &cells, &env), "(5)");
+        let res = eval_str("(flatten ())", &mut cells, &mut env, &ns).unwrap();
+        assert_eq!(res, NIL_INDEX);
+    }
+
+    #[test]
+    fn truthiness_policies() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let zero = eval_str("0", &mut cells, &mut env, &ns).unwrap();
+        let one = eval_str("1", &mut cells, &mut env, &ns).unwrap();
+        let nil = eval_str("()", &mut cells, &mut env, &ns).unwrap();
+
+        assert_eq!(env.truthiness, Truthiness::NilOnly);
+        assert!(is_truthy(zero, &cells, &env));
+        assert!(is_truthy(one, &cells, &env));
+        assert!(!is_truthy(nil, &cells, &env));
+
+        env.truthiness = Truthiness::NilAndZero;
+        assert!(!is_truthy(zero, &cells, &env));
+        assert!(is_truthy(one, &cells, &env));
+        assert!(!is_truthy(nil, &cells, &env));
+    }
+
+    #[test]
+    fn conditions_follow_the_truthiness_policy() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        eval_str("(defmacro id (x) x)", &mut cells, &mut env, &ns).unwrap();
+
+        for &(policy, count, run) in [(Truthiness::NilOnly, 4, "(1 0 2)"),
+                                      (Truthiness::NilAndZero, 2, "(1)")]
+            .iter() {
+            env.truthiness = policy;
+            let res = eval_str("(count id ('(0 1 2 0)))", &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, count, &cells);
+            let res = eval_str("(take-while id ('(1 0 2)))", &mut cells, &mut env, &ns)
+                .unwrap();
+            assert_eq!(exp_to_string(res, &cells, &env), run, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn step_limit_stops_long_evaluations() {
+        let mut buf = [Cell::empty(); 512];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.step_limit = Some(50);
+
+        match eval_str("(reduce add (range 100))", &mut cells, &mut env, &ns) {
+            Err(EvalError::StepLimit) => {}
+            other => panic!("expected the step limit, got {:?}", other),
+        }
+        env.steps = 0;
+        let res = eval_str("(reduce add (range 5))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 10, &cells);
+        // Catching doesn't buy more steps.
+        env.steps = 0;
+        match eval_str("(catch (reduce add (range 100)) 0)", &mut cells, &mut env, &ns) {
+            Err(EvalError::StepLimit) => {}
+            other => panic!("expected the step limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(add 7 3)", 10),
+                                   ("(sub 7 3)", 4),
+                                   ("(mul 7 3)", 21),
+                                   ("(div 7 3)", 2),
+                                   ("(mod 7 3)", 1)]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, expected, &cells);
+        }
+        // Something that isn't arithmetic doesn't fall through to `mod`.
+        let buf = b"(cons 7 3)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        match eval_arithmetic(ns.cons, exp, &mut cells, &mut env, &ns) {
+            Err(EvalError::UnknownOperator(op)) => assert_eq!(op, ns.cons),
+            other => panic!("expected an unknown operator, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn random_follows_the_seed() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        env.rng = Rng::new(42);
+
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            let res = eval_str("(random 10)", &mut cells, &mut env, &ns).unwrap();
+            match cells.val_of(res) {
+                CellType::Number(n) => seen.push(n),
+                other => panic!("expected a number, got {:?}", other),
+            }
+        }
+        assert_eq!(seen, vec![3, 1, 8, 4, 0, 2, 5, 8]);
+        match eval_str("(random 0)", &mut cells, &mut env, &ns) {
+            Err(EvalError::NotPositive(0)) => {}
+            other => panic!("expected a non-positive error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_sequence() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            eval_str("(set-seed! 7)", &mut cells, &mut env, &ns).unwrap();
+            let mut seen = Vec::new();
+            for _ in 0..10 {
+                let res = eval_str("(random 1000)", &mut cells, &mut env, &ns).unwrap();
+                seen.push(exp_to_string(res, &cells, &env));
+            }
+            runs.push(seen);
+        }
+        assert_eq!(runs[0], runs[1]);
+        eval_str("(set-seed! 8)", &mut cells, &mut env, &ns).unwrap();
+        let res = eval_str("(random 1000)", &mut cells, &mut env, &ns).unwrap();
+        assert_ne!(exp_to_string(res, &cells, &env), runs[0][0]);
+    }
+
+    #[test]
+    fn cell_counts_add_up_to_capacity() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(add (cells-used) (cells-free))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 64, &cells);
+        // Reading the form takes two cells.
+        let before = cells.free_count();
+        let res = eval_str("(cells-free)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, before as i32 - 2, &cells);
+
+        // Past the interned numbers the answer to `cells-used` needs a cell.
+        let mut buf = [Cell::empty(); 512];
+        let mut cells = init_storage(&mut buf);
+        for _ in 0..300 {
+            cells.alloc_cell(CellType::Number(1000));
+        }
+        let res = eval_str("(add (cells-used) (cells-free))", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 512, &cells);
+    }
+
+    #[test]
+    fn small_arithmetic_takes_no_cells() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let buf = b"(add (mul 3 4) (sub 0 5))".to_vec();
+        let (form, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let free = cells.free_count();
+        for _ in 0..100 {
+            let res = eval(form, &mut cells, &mut env, &ns);
+            assert_number(res.unwrap(), 7, &cells);
+        }
+        assert_eq!(cells.free_count(), free);
+
+        let res = eval_str("(mul 100 100)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 10000, &cells);
+        assert!(!cells.is_interned(res));
+    }
+
+    #[test]
+    fn time_ms_is_monotonic() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let first = eval_str("(time-ms)", &mut cells, &mut env, &ns).unwrap();
+        let second = eval_str("(time-ms)", &mut cells, &mut env, &ns).unwrap();
+        match (cells.val_of(first), cells.val_of(second)) {
+            (CellType::Number(a), CellType::Number(b)) => assert!(0 <= a && a <= b),
+            other => panic!("expected two numbers, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn quotient_truncates() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let res = eval_str("(quotient 7 2)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, 3, &cells);
+        let res = eval_str("(quotient (sub 0 7) 2)", &mut cells, &mut env, &ns).unwrap();
+        assert_number(res, -3, &cells);
+        match eval_str("(quotient 7 0)", &mut cells, &mut env, &ns) {
+            Err(EvalError::DivideByZero) => {}
+            other => panic!("expected division by zero, got {:?}", other),
+        }
+        let min = "(sub (sub 0 2147483647) 1)";
+        match eval_str(&format!("(quotient {} (sub 0 1))", min), &mut cells, &mut env, &ns) {
+            Err(EvalError::Overflow) => {}
+            other => panic!("expected an overflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arithmetic_errors_rather_than_panicking() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for input in ["(div 1 0)", "(mod 1 0)", "(modulo 1 0)", "(remainder 1 0)"].iter() {
+            match eval_str(input, &mut cells, &mut env, &ns) {
+                Err(EvalError::DivideByZero) => {}
+                other => panic!("expected {} to divide by zero, got {:?}", input, other),
+            }
+        }
+        let min = "(sub (sub 0 2147483647) 1)";
+        for input in ["(add 2147483647 1)".to_string(),
+                      format!("(sub {} 1)", min),
+                      "(mul 65536 65536)".to_string(),
+                      format!("(div {} (sub 0 1))", min)]
+            .iter() {
+            match eval_str(input, &mut cells, &mut env, &ns) {
+                Err(EvalError::Overflow) => {}
+                other => panic!("expected {} to overflow, got {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn modulo_and_remainder() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(modulo (sub 0 7) 3)", 2),
+                                   ("(remainder (sub 0 7) 3)", -1),
+                                   ("(mod (sub 0 7) 3)", -1),
+                                   ("(modulo 7 (sub 0 3))", -2),
+                                   ("(remainder 7 (sub 0 3))", 1),
+                                   ("(modulo 7 3)", 1),
+                                   ("(modulo (sub 0 6) 3)", 0)]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, expected, &cells);
+        }
+        let buf = b"(modulo (sub 0 7) 3)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        assert_eq!(fold_constants(exp, &cells, &mut env, &ns), Some(2));
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let mut buf = [Cell::empty(); 128];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for &(input, expected) in [("(bit-and 12 10)", 8),
+                                   ("(bit-or 12 10)", 14),
+                                   ("(bit-xor 12 10)", 6),
+                                   ("(bit-not 0)", -1),
+                                   ("(shl 1 4)", 16),
+                                   ("(shr 256 4)", 16),
+                                   ("(shr (sub 0 16) 2)", -4)]
+            .iter() {
+            let res = eval_str(input, &mut cells, &mut env, &ns).unwrap();
+            assert_number(res, expected, &cells);
+        }
+        for input in ["(shl 1 32)", "(shr 1 (sub 0 1))"].iter() {
+            match eval_str(input, &mut cells, &mut env, &ns) {
+                Err(EvalError::BadShift(_)) => {}
+                other => panic!("expected a bad shift error, got {:?}", other),
+            }
+        }
     }
 }
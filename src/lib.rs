@@ -1,3 +1,10 @@
+#[macro_use]
+pub mod cell;
+pub mod env;
+pub mod parser;
+pub mod eval;
+pub mod vm;
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -1,67 +1,273 @@
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-#[macro_use]
-mod cell;
-mod env;
-mod parser;
-mod eval;
-
-use cell::*;
-use parser::*;
-use env::*;
-use eval::{EvalError, eval};
-
-fn s_exp(input: &mut io::StdinLock,
-         output: &mut io::Stdout,
-         mut buf: &mut Vec<u8>,
-         storage: &mut CellStorage,
-         env: &mut Env)
-         -> Option<CellIndex> {
-    let mut parser = Parser::new(env);
+extern crate yetanotherlisp;
+#[cfg(feature = "readline")]
+extern crate rustyline;
+
+use yetanotherlisp::cell::*;
+use yetanotherlisp::parser::*;
+use yetanotherlisp::env::*;
+use yetanotherlisp::eval::{EvalError, describe_err, eval, fold_constants, reclaim};
+
+/// Settings that only concern the REPL, changed with `:` commands.
+#[derive(Default)]
+struct ReplOptions {
+    /// Print each form as it was read before its value.
+    echo: bool,
+    /// Where `:transcript` is logging forms and their results, if anywhere.
+    transcript: Option<File>,
+}
+
+/// Where the REPL gets its lines from.
+trait LineReader {
+    /// Shows `prompt` and appends the next line, newline included, to `buf`.
+    /// Returns the number of bytes read, which is 0 at the end of input.
+    fn read_line<W: Write>(&mut self,
+                           prompt: &str,
+                           output: &mut W,
+                           buf: &mut Vec<u8>)
+                           -> io::Result<usize>;
+}
+
+impl<R: BufRead> LineReader for R {
+    fn read_line<W: Write>(&mut self,
+                           prompt: &str,
+                           output: &mut W,
+                           buf: &mut Vec<u8>)
+                           -> io::Result<usize> {
+        write!(output, "{}", prompt)?;
+        output.flush()?;
+        self.read_until(b'\n', buf)
+    }
+}
+
+/// Line editing and in-memory history for an interactive terminal.
+#[cfg(feature = "readline")]
+struct Readline(rustyline::DefaultEditor);
+
+#[cfg(feature = "readline")]
+impl LineReader for Readline {
+    fn read_line<W: Write>(&mut self,
+                           prompt: &str,
+                           _output: &mut W,
+                           buf: &mut Vec<u8>)
+                           -> io::Result<usize> {
+        use rustyline::error::ReadlineError;
+
+        match self.0.readline(prompt) {
+            Ok(line) => {
+                let _ = self.0.add_history_entry(line.as_str());
+                buf.extend_from_slice(line.as_bytes());
+                buf.push(b'\n');
+                Ok(line.len() + 1)
+            }
+            Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => Ok(0),
+            Err(ReadlineError::Io(err)) => Err(err),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+fn s_exp<R: LineReader, W: Write>(input: &mut R,
+                                  output: &mut W,
+                                  buf: &mut Vec<u8>,
+                                  storage: &mut CellStorage,
+                                  env: &mut Env,
+                                  opts: &mut ReplOptions)
+                                  -> io::Result<Option<CellIndex>> {
+    let mut nesting = 0;
     loop {
-        print!("[{}] ", parser.nesting);
-        output.flush().unwrap();
-        if let Ok(n) = input.read_until(b'\n', &mut buf) {
-            // Check for EOF
+        // A blank or comment-only line holds nothing to parse, so prompt
+        // again afresh.
+        if nesting == 0 && is_blank(buf) {
+            buf.clear();
+        }
+        // Whatever followed the previous form may already hold the next one.
+        if !buf.is_empty() {
+            let line = String::from_utf8_lossy(buf).trim().to_string();
+            if nesting == 0 && line.starts_with(':') {
+                run_command(&line, output, storage, env, opts)?;
+                buf.clear();
+                continue;
+            }
+            let mut parser = Parser::new(env);
+            match parser.parse(buf, storage) {
+                Ok((idx, consumed)) => {
+                    buf.drain(..consumed);
+                    // Don't let the rest of the line shift the spans of the
+                    // next form.
+                    if is_blank(buf) {
+                        buf.clear();
+                    }
+                    return Ok(Some(idx));
+                }
+                Err(ParseError::SyntaxError(ch)) => {
+                    writeln!(output, "Syntax error at '{}'", ch)?;
+                    buf.clear();
+                }
+                // Wait for the rest of the form.
+                Err(ParseError::UnexpectedEof(_)) => {}
+            }
+            nesting = parser.nesting;
+        }
+        if let Ok(n) = input.read_line(&format!("[{}] ", nesting), output, buf) {
+            // Check for EOF. A complete last form needs no newline after it,
+            // having been parsed above, so anything left is unfinished.
             if n == 0 {
-                return None;
+                if nesting > 0 {
+                    writeln!(output, "Unexpected end of input")?;
+                }
+                return Ok(None);
             }
         }
         // println!("{}", String::from_utf8_lossy(buf));
-        match parser.parse(buf, storage) {
-            Ok(idx) => return Some(idx),
-            Err(ParseError::SyntaxError(ch)) => {
-                println!("Syntax error at '{}'", ch);
-                buf.clear();
+    }
+}
+
+/// Handles a `:command` line typed at the top level of the REPL.
+fn run_command<W: Write>(line: &str,
+                         output: &mut W,
+                         storage: &CellStorage,
+                         env: &mut Env,
+                         opts: &mut ReplOptions)
+                         -> io::Result<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match &words[..] {
+        [":dump"] => write!(output, "{}", dump_storage(storage, env)),
+        [":echo", "on"] => {
+            opts.echo = true;
+            Ok(())
+        }
+        [":echo", "off"] => {
+            opts.echo = false;
+            Ok(())
+        }
+        [":circle", "on"] => {
+            env.print_circle = true;
+            Ok(())
+        }
+        [":circle", "off"] => {
+            env.print_circle = false;
+            Ok(())
+        }
+        [":radix", radix] => {
+            match radix.parse() {
+                Ok(radix @ 2) | Ok(radix @ 8) | Ok(radix @ 10) | Ok(radix @ 16) => {
+                    env.radix = radix;
+                    Ok(())
+                }
+                _ => writeln!(output, "Radix must be 2, 8, 10 or 16, got '{}'", radix),
+            }
+        }
+        [":seed", seed] => {
+            match seed.parse() {
+                Ok(seed) => {
+                    env.rng = Rng::new(seed);
+                    Ok(())
+                }
+                Err(_) => writeln!(output, "Expected a seed number, got '{}'", seed),
+            }
+        }
+        [":time-limit", "off"] => {
+            env.step_limit = None;
+            Ok(())
+        }
+        [":time-limit", steps] => {
+            match steps.parse() {
+                Ok(steps) => {
+                    env.step_limit = Some(steps);
+                    Ok(())
+                }
+                Err(_) => writeln!(output, "Expected a number of steps, got '{}'", steps),
+            }
+        }
+        [":transcript", "off"] => {
+            opts.transcript = None;
+            Ok(())
+        }
+        [":transcript", path] => {
+            match File::create(path) {
+                Ok(file) => {
+                    opts.transcript = Some(file);
+                    Ok(())
+                }
+                Err(err) => writeln!(output, "Can't open transcript '{}': {}", path, err),
             }
-            _ => {}
         }
+        _ => writeln!(output, "Unknown command '{}'", line),
     }
 }
 
-fn init_storage(buf: &mut [Cell]) -> CellStorage {
-    let count = buf.len() - 1;
-    let mut storage = CellStorage::new(buf);
-    for idx in 1..count {
-        storage.set_tail(idx, idx + 1);
-    }
-    storage
+fn display_err<W: Write>(output: &mut W,
+                         err_type: EvalError,
+                         cells: &CellStorage,
+                         env: &Env)
+                         -> io::Result<()> {
+    write!(output, "Error: ")?;
+    describe_err(output, err_type, cells, env)
 }
 
-fn display_err(err_type: EvalError, cells: &CellStorage, env: &Env) {
-    print!("\nError: ");
-    match err_type {
-        EvalError::IllegalOperator => println!("illegal operator!"),
-        EvalError::NonUnary => println!("non unary expression!"),
-        EvalError::NotCons(exp) => {
-            print_exp(exp, cells, env);
-            println!(" does not evaluate to a cons pair!");
+/// Reads, evaluates and prints forms from `input` until it runs out.
+fn repl<R: LineReader, W: Write>(input: &mut R,
+                                 output: &mut W,
+                                 storage: &mut CellStorage,
+                                 env: &mut Env,
+                                 ns: &DefaultNS,
+                                 opts: &mut ReplOptions)
+                                 -> io::Result<()> {
+    let mut buf = Vec::with_capacity(64);
+    while let Some(idx) = s_exp(input, output, &mut buf, storage, env, opts)? {
+        let form = exp_to_string(idx, storage, env);
+        if opts.echo {
+            write!(output, "{}", form)?;
+        }
+        if env.fold_constants {
+            fold_constants(idx, storage, env, ns);
+        }
+        env.steps = 0;
+        storage.start_tracking();
+        let res = eval(idx, storage, env, ns);
+        let allocated = storage.stop_tracking();
+        // Whatever the form printed goes on its own lines before its value.
+        let printed = env.take_printed();
+        if opts.echo && !printed.is_empty() {
+            writeln!(output)?;
+        }
+        write!(output, "{}", printed)?;
+        if let Some(ref mut file) = opts.transcript {
+            write!(file, "{}{} ==> ", printed, form)?;
+        }
+        match res {
+            Ok(exp) => {
+                if opts.echo {
+                    write!(output, " ==> ")?;
+                }
+                let value = exp_to_string(exp, storage, env);
+                writeln!(output, "{}", value)?;
+                if let Some(ref mut file) = opts.transcript {
+                    writeln!(file, "{}", value)?;
+                }
+                storage.free_cell(exp, env);
+            }
+            Err(err_type) => {
+                if opts.echo {
+                    writeln!(output)?;
+                }
+                let mut message = Vec::new();
+                display_err(&mut message, err_type, storage, env)?;
+                reclaim(allocated, storage, env);
+                output.write_all(&message)?;
+                if let Some(ref mut file) = opts.transcript {
+                    file.write_all(&message)?;
+                }
+            }
         }
-        EvalError::NonBinary => println!("non binary expression!"),
-        EvalError::NonNumeric => println!("non unary expression!"),
-        EvalError::UnknownOperator(op) => println!("unknown operator '{}'", env.get_sym(op)),
+
+        storage.free_cell(idx, env);
     }
+    Ok(())
 }
 
 fn main() {
@@ -69,35 +275,260 @@ fn main() {
     let mut storage = init_storage(&mut cells);
     let mut env = Env::new();
     let ns = DefaultNS::new(&mut env);
+    env.strict = std::env::args().any(|arg| arg == "--strict");
+    env.fold_constants = std::env::args().any(|arg| arg == "--fold");
+    env.error_context = true;
+    env.exit_process = true;
+    env.spans = Some(SpanTable::new());
 
     println!("An S-expression Evaluator.");
-    let stdin = io::stdin();
-    let mut buf = Vec::with_capacity(64);
-    let mut input = stdin.lock();
     let mut output = io::stdout();
+    let mut opts = ReplOptions::default();
 
-    loop {
-        if let Some(idx) = s_exp(&mut input, &mut output, &mut buf, &mut storage, &mut env) {
-            print_exp(idx, &mut storage, &env);
-            match eval(idx, &mut storage, &mut env, &ns) {
-                Ok(exp) => {
-                    print!(" ==> ");
-                    print_exp(exp, &storage, &env);
-                    storage.free_cell(exp);
-                    println!("");
-                }
-                Err(err_type) => {
-                    display_err(err_type, &storage, &env);
-                }
-            }
+    #[cfg(feature = "readline")]
+    {
+        use std::io::IsTerminal;
 
-            storage.free_cell(idx);
-            // println!("{:?}", &storage);
-            buf.clear();
-        } else {
-            break;
+        if io::stdin().is_terminal() {
+            if let Ok(editor) = rustyline::DefaultEditor::new() {
+                let mut input = Readline(editor);
+                repl(&mut input, &mut output, &mut storage, &mut env, &ns, &mut opts).unwrap();
+                println!("\nEnd.");
+                return;
+            }
         }
     }
 
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    repl(&mut input, &mut output, &mut storage, &mut env, &ns, &mut opts).unwrap();
+
     println!("\nEnd.");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_repl(input: &str) -> String {
+        let mut buf = [Cell::empty(); 256];
+        let mut storage = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        let mut opts = ReplOptions::default();
+
+        let mut output = Vec::new();
+        repl(&mut input.as_bytes(),
+             &mut output,
+             &mut storage,
+             &mut env,
+             &ns,
+             &mut opts)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn failed_evaluations_give_back_their_cells() {
+        let mut buf = [Cell::empty(); 64];
+        let mut storage = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        let mut opts = ReplOptions::default();
+        let baseline = storage.free_count();
+
+        for _ in 0..100 {
+            let mut output = Vec::new();
+            repl(&mut "(add (cons 1 (make-list 3 4)) (hd 5))\n".as_bytes(),
+                 &mut output,
+                 &mut storage,
+                 &mut env,
+                 &ns,
+                 &mut opts)
+                .unwrap();
+            assert!(String::from_utf8(output).unwrap().contains("Error: "));
+            assert_eq!(storage.free_count(), baseline);
+        }
+    }
+
+    #[test]
+    fn vectors_tables_and_promises_give_back_their_cells() {
+        let mut buf = [Cell::empty(); 64];
+        let mut storage = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        let mut opts = ReplOptions::default();
+        let baseline = storage.free_count();
+
+        for input in ["(vector 1)\n",
+                      "(hd (vector 1))\n",
+                      "(make-hash)\n",
+                      "(hd (make-hash))\n",
+                      "(delay (cons 1 2))\n",
+                      "(hd (delay 1000))\n"]
+            .iter() {
+            for _ in 0..100 {
+                let mut output = Vec::new();
+                repl(&mut input.as_bytes(),
+                     &mut output,
+                     &mut storage,
+                     &mut env,
+                     &ns,
+                     &mut opts)
+                    .unwrap();
+                assert_eq!(storage.free_count(), baseline, "{}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn running_out_of_cells_is_an_error() {
+        let mut buf = [Cell::empty(); 64];
+        let mut storage = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        let mut opts = ReplOptions::default();
+        let baseline = storage.free_count();
+
+        let mut output = Vec::new();
+        repl(&mut "(symbols)\n(add 1 2)\n".as_bytes(),
+             &mut output,
+             &mut storage,
+             &mut env,
+             &ns,
+             &mut opts)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "[0] Error: out of cell storage!\n[0] 3\n[0] ");
+        assert_eq!(storage.free_count(), baseline);
+    }
+
+    #[test]
+    fn plain_input_reads_multiline_forms() {
+        assert_eq!(run_repl("(add 1\n2) (mul 2\n3)\n"), "[0] [1] 3\n[1] 6\n[0] ");
+    }
+
+    #[test]
+    fn echo_prints_form_before_value() {
+        assert_eq!(run_repl("('x)\n"), "[0] x\n[0] ");
+        assert_eq!(run_repl(":echo on\n('x)\n"), "[0] [0] (' x) ==> x\n[0] ");
+        assert_eq!(run_repl(":echo on\n:echo off\n('x)\n"), "[0] [0] [0] x\n[0] ");
+    }
+
+    #[test]
+    fn blank_and_comment_lines_are_ignored() {
+        assert_eq!(run_repl("\n   \n; just a comment\n(add 1 2) ; trailing\n\n"),
+                   "[0] [0] [0] [0] 3\n[0] [0] ");
+        assert_eq!(run_repl("(add 1\n\n; still waiting\n2)\n"), "[0] [1] [1] [1] 3\n[0] ");
+    }
+
+    #[test]
+    fn time_limit_bounds_each_evaluation() {
+        assert_eq!(run_repl(":time-limit 20\n(reduce add (range 50))\n(reduce add (range 5))\n"),
+                   "[0] [0] Error: step limit reached!\n[0] 10\n[0] ");
+        assert_eq!(run_repl(":time-limit 20\n:time-limit off\n(reduce add (range 50))\n"),
+                   "[0] [0] [0] 1225\n[0] ");
+    }
+
+    #[test]
+    fn macros_outlive_the_line_defining_them() {
+        assert_eq!(run_repl("(defmacro swap (a b) (cons b a))\n(swap 1 2)\n(swap 3 4)\n"),
+                   "[0] swap\n[0] (2 . 1)\n[0] (4 . 3)\n[0] ");
+    }
+
+    #[test]
+    fn macro_bodies_survive_repeated_use() {
+        let input = "(defmacro k (x) foo)\n(k 1)\n(k 1)\n(k 1)\n";
+        assert_eq!(run_repl(input), "[0] k\n[0] foo\n[0] foo\n[0] foo\n[0] ");
+        let input = "(defmacro k (x) 1000)\n(k 1)\n(k 1)\n(k 1)\n";
+        assert_eq!(run_repl(input), "[0] k\n[0] 1000\n[0] 1000\n[0] 1000\n[0] ");
+        let input = "(defmacro m (x) (' (a b)))\n(m 1)\n(m 1)\n(m 1)\n";
+        assert_eq!(run_repl(input), "[0] m\n[0] (a b)\n[0] (a b)\n[0] (a b)\n[0] ");
+    }
+
+    #[test]
+    fn circle_labels_shared_structure() {
+        // The macro puts the same argument cell in both places.
+        let input = "(defmacro twice (x) (' (x x)))\n(twice (1 2))\n:circle on\n(twice (1 2))\n";
+        assert_eq!(run_repl(input),
+                   "[0] twice\n[0] ((1 2) (1 2))\n[0] [0] (#0=(1 2) #0#)\n[0] ");
+    }
+
+    #[test]
+    fn radix_changes_how_numbers_print() {
+        assert_eq!(run_repl(":radix 16\n(add 250 5)\n:radix 10\n255\n"),
+                   "[0] [0] 0xff\n[0] [0] 255\n[0] ");
+        assert_eq!(run_repl(":radix 3\n"), "[0] Radix must be 2, 8, 10 or 16, got '3'\n[0] ");
+    }
+
+    #[test]
+    fn seed_command_restarts_random() {
+        let draws = ":seed 5\n(random 100)\n(random 100)\n";
+        let output = run_repl(&draws.repeat(2));
+        let values: Vec<&str> = output.split("[0] ").filter(|value| !value.is_empty()).collect();
+        assert_eq!(values.len(), 4);
+        assert_eq!(values[..2], values[2..]);
+    }
+
+    #[test]
+    fn end_of_input_terminates_the_last_form() {
+        assert_eq!(run_repl("(add 1 2)"), "[0] 3\n[0] ");
+        assert_eq!(run_repl("4\n(mul 2\n3)"), "[0] 4\n[0] [1] 6\n[0] ");
+        assert_eq!(run_repl("(add 1\n(mul 2"), "[0] [1] [2] Unexpected end of input\n");
+    }
+
+    #[test]
+    fn transcript_logs_forms_and_results() {
+        let path = std::env::temp_dir().join(format!("yal-transcript-{}.txt", std::process::id()));
+        let input = format!(":transcript {}\n(add 1 2)\n(hd 1)\n:transcript off\n(add 3 4)\n",
+                            path.display());
+        run_repl(&input);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log,
+                   "(add 1 2) ==> 3\n(hd 1) ==> Error: (hd 1) does not evaluate to a cons pair!\n");
+    }
+
+    #[test]
+    fn help_prints_through_the_repl() {
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+        let text = ns.help(ns.add).unwrap();
+        assert_eq!(run_repl("(help add)\n(help frobnicate)\n"),
+                   format!("[0] {}\nt\n[0] no help available\n()\n[0] ", text));
+
+        let path = std::env::temp_dir().join(format!("yal-help-{}.txt", std::process::id()));
+        run_repl(&format!(":transcript {}\n(help add)\n", path.display()));
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log, format!("{}\n(help add) ==> t\n", text));
+    }
+
+    #[test]
+    fn pp_prints_through_the_repl() {
+        let list = "(fold-right add 0 (range (mul 10 10)) (1 2 3 4 5 6 7 8 9 10 11 12 13 14))";
+        let pretty = "(fold-right\n add\n 0\n (range (mul 10 10))\n \
+                      (1 2 3 4 5 6 7 8 9 10 11 12 13 14))\n";
+        assert_eq!(run_repl(&format!("(pp ('{}))\n", list)),
+                   format!("[0] {}{}\n[0] ", pretty, list));
+
+        let path = std::env::temp_dir().join(format!("yal-pp-{}.txt", std::process::id()));
+        run_repl(&format!(":transcript {}\n(pp ('{}))\n", path.display(), list));
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log, format!("{}(pp (' {})) ==> {}\n", pretty, list, list));
+    }
+
+    #[test]
+    fn draw_prints_through_the_repl() {
+        let picture = "[*|*]--->[*|/]\n |        |\n 1        2\n";
+        assert_eq!(run_repl("(draw ('(1 2)))\n"),
+                   format!("[0] {}(1 2)\n[0] ", picture));
+
+        let path = std::env::temp_dir().join(format!("yal-draw-{}.txt", std::process::id()));
+        run_repl(&format!(":transcript {}\n(draw ('(1 2)))\n", path.display()));
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log, format!("{}(draw (' (1 2))) ==> (1 2)\n", picture));
+    }
+}
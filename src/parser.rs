@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
 use env::Env;
 use cell::*;
 
@@ -8,17 +11,76 @@ pub enum Token {
     Dot,
     Number(String),
     Symbol(String),
-    EOL,
+    Eol,
+}
+fn is_symbol_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '-' || ch == '?' || ch == '>' || ch == '!'
+}
+
+/// The line and column (both counting from 1) a cell was parsed from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+}
+
+/// Side table from parsed cells to where they came from in the input.
+#[derive(Debug, Default)]
+pub struct SpanTable {
+    spans: HashMap<CellIndex, Span>,
 }
+impl SpanTable {
+    pub fn new() -> Self {
+        SpanTable { spans: HashMap::new() }
+    }
+
+    pub fn record(&mut self, idx: CellIndex, span: Span) {
+        self.spans.insert(idx, span);
+    }
+
+    pub fn get(&self, idx: CellIndex) -> Option<Span> {
+        self.spans.get(&idx).cloned()
+    }
+
+    /// Forgets `idx`'s span, for when the cell is freed and may be reused.
+    pub fn remove(&mut self, idx: CellIndex) {
+        self.spans.remove(&idx);
+    }
+
+    pub fn clear(&mut self) {
+        self.spans.clear();
+    }
+}
+
+/// Whether `input` holds nothing but whitespace and comments.
+pub fn is_blank(input: &Vec<u8>) -> bool {
+    let mut tokens = TokenStream::new(input);
+    tokens.consume_whitespace();
+    tokens.eol()
+}
+
 struct TokenStream<'a> {
     input: &'a Vec<u8>,
     pos: usize,
+    /// Where the most recently read token started.
+    start: usize,
 }
 impl<'a> TokenStream<'a> {
     fn new(input: &'a Vec<u8>) -> Self {
         TokenStream {
-            input: input,
+            input,
             pos: 0,
+            start: 0,
+        }
+    }
+
+    /// The span of the most recently read token.
+    fn span(&self) -> Span {
+        let before = &self.input[..self.start];
+        let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        Span {
+            line: before.iter().filter(|&&b| b == b'\n').count() as u32 + 1,
+            col: (self.start - line_start) as u32 + 1,
         }
     }
 
@@ -33,20 +95,37 @@ impl<'a> TokenStream<'a> {
     fn next_ch(&mut self) -> char {
         let ch = self.input[self.pos];
         self.pos += 1;
-        return ch as char;
+        ch as char
+    }
+
+    /// Where the stream is now, to `restore` to later.
+    fn checkpoint(&self) -> usize {
+        self.pos
+    }
+
+    fn restore(&mut self, checkpoint: usize) {
+        self.pos = checkpoint;
+    }
+
+    /// Lets tokens be read speculatively: the stream goes back to where it
+    /// was when the guard is dropped, unless it's committed first.
+    fn speculate(&mut self) -> Speculation<'_, 'a> {
+        Speculation {
+            checkpoint: self.checkpoint(),
+            tokens: self,
+            committed: false,
+        }
     }
 
     fn peek_token(&mut self) -> Result<Token, ParseError> {
-        let old_pos = self.pos;
-        let tok = self.next_token();
-        self.pos = old_pos;
-        tok
+        self.speculate().next_token()
     }
 
     fn next_token(&mut self) -> Result<Token, ParseError> {
         self.consume_whitespace();
+        self.start = self.pos;
         if self.eol() {
-            Ok(Token::EOL)
+            Ok(Token::Eol)
         } else {
             let ch = self.peek_ch();
             match ch {
@@ -68,10 +147,10 @@ impl<'a> TokenStream<'a> {
                     Ok(Token::Symbol(String::from("'")))
                 }
                 _ => {
-                    if ch.is_digit(10) {
-                        Ok(Token::Number(self.consume_while(|c| c.is_digit(10))))
+                    if ch.is_ascii_digit() {
+                        Ok(Token::Number(self.consume_while(|c| c.is_ascii_digit())))
                     } else if ch.is_alphanumeric() {
-                        Ok(Token::Symbol(self.consume_while(char::is_alphanumeric)))
+                        Ok(Token::Symbol(self.consume_while(is_symbol_char)))
                     } else {
                         Err(ParseError::SyntaxError(ch))
                     }
@@ -89,17 +168,57 @@ impl<'a> TokenStream<'a> {
             res.push(self.next_ch());
         }
 
-        return res;
+        res
     }
 
+    /// Skips whitespace and `;` comments, which run to the end of the line.
     fn consume_whitespace(&mut self) {
         self.consume_while(char::is_whitespace);
+        while !self.eol() && self.peek_ch() == ';' {
+            self.consume_while(|c| c != '\n');
+            self.consume_while(char::is_whitespace);
+        }
+    }
+}
+
+struct Speculation<'s, 'a: 's> {
+    tokens: &'s mut TokenStream<'a>,
+    checkpoint: usize,
+    committed: bool,
+}
+impl<'s, 'a> Speculation<'s, 'a> {
+    /// Keeps the tokens read so far.
+    fn commit(mut self) {
+        self.committed = true;
     }
 }
+impl<'s, 'a> Deref for Speculation<'s, 'a> {
+    type Target = TokenStream<'a>;
 
+    fn deref(&self) -> &TokenStream<'a> {
+        self.tokens
+    }
+}
+impl<'s, 'a> DerefMut for Speculation<'s, 'a> {
+    fn deref_mut(&mut self) -> &mut TokenStream<'a> {
+        self.tokens
+    }
+}
+impl<'s, 'a> Drop for Speculation<'s, 'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let checkpoint = self.checkpoint;
+            self.tokens.restore(checkpoint);
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum ParseError {
     SyntaxError(char),
-    UnbalancedBraces(u32),
+    /// The input ran out inside a form, this many parentheses deep. More
+    /// input may still complete it.
+    UnexpectedEof(u32),
 }
 
 pub struct Parser<'a> {
@@ -109,18 +228,24 @@ pub struct Parser<'a> {
 impl<'a> Parser<'a> {
     pub fn new(env: &'a mut Env) -> Self {
         Parser {
-            env: env,
+            env,
             nesting: 0,
         }
     }
 
+    /// Parses the first expression in `input`, returning it along with the
+    /// number of bytes it took up.
     pub fn parse(&mut self,
                  input: &Vec<u8>,
                  storage: &mut CellStorage)
-                 -> Result<CellIndex, ParseError> {
+                 -> Result<(CellIndex, usize), ParseError> {
         self.nesting = 0;
+        if let Some(ref mut spans) = self.env.spans {
+            spans.clear();
+        }
         let mut tokens = TokenStream::new(input);
-        self.parse_sexp(&mut tokens, storage)
+        let exp = self.parse_sexp(&mut tokens, storage)?;
+        Ok((exp, tokens.pos))
     }
 
     fn next_tok(&mut self, tokens: &mut TokenStream) -> Result<Token, ParseError> {
@@ -129,7 +254,19 @@ impl<'a> Parser<'a> {
                 self.nesting = 0;
                 err
             }
-            res @ _ => res,
+            res => res,
+        }
+    }
+
+    /// The error for finding `tok` where a datum or a closing parenthesis
+    /// should be: running out of input means the form isn't finished yet,
+    /// anything else is a syntax error at the token.
+    fn unexpected(&mut self, tok: Token, tokens: &TokenStream) -> ParseError {
+        if let Token::Eol = tok {
+            ParseError::UnexpectedEof(self.nesting)
+        } else {
+            self.nesting = 0;
+            ParseError::SyntaxError(tokens.input[tokens.start] as char)
         }
     }
 
@@ -139,7 +276,23 @@ impl<'a> Parser<'a> {
                 self.nesting = 0;
                 err
             }
-            res @ _ => res,
+            res => res,
+        }
+    }
+
+    /// Consumes the next token if it's a dot, saying whether it was.
+    fn skip_dot(&mut self, tokens: &mut TokenStream) -> Result<bool, ParseError> {
+        let mut spec = tokens.speculate();
+        match spec.next_token() {
+            Ok(Token::Dot) => {
+                spec.commit();
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) => {
+                self.nesting = 0;
+                Err(err)
+            }
         }
     }
 
@@ -147,7 +300,9 @@ impl<'a> Parser<'a> {
                   tokens: &mut TokenStream,
                   storage: &mut CellStorage)
                   -> Result<CellIndex, ParseError> {
-        match try!(self.next_tok(tokens)) {
+        let tok = self.next_tok(tokens)?;
+        let span = tokens.span();
+        let res = match tok {
             Token::Number(str_num) => {
                 let nval = str_num.parse::<i32>().unwrap();
                 Ok(storage.alloc_cell(CellType::Number(nval)))
@@ -156,29 +311,39 @@ impl<'a> Parser<'a> {
             Token::LeftParen => {
                 self.nesting += 1;
                 self.parse_sexps(tokens, storage).and_then(|exps| {
-                    self.nesting -= 1;
-                    self.next_tok(tokens).map(|val| {
-                        assert_eq!(val, Token::RightParen);
-                        exps
-                    })
+                    match self.next_tok(tokens)? {
+                        Token::RightParen => {
+                            self.nesting -= 1;
+                            Ok(exps)
+                        }
+                        // More than one datum after a dot.
+                        tok => Err(self.unexpected(tok, tokens)),
+                    }
                 })
             }
-            _ => Err(ParseError::UnbalancedBraces(self.nesting)),
+            tok => Err(self.unexpected(tok, tokens)),
+        };
+        if let (Ok(idx), Some(spans)) = (res.as_ref(), self.env.spans.as_mut()) {
+            // Interned numbers are shared by every occurrence, so they have no
+            // single span.
+            if *idx != NIL_INDEX && !storage.is_interned(*idx) {
+                spans.record(*idx, span);
+            }
         }
+        res
     }
 
     fn parse_sexps(&mut self,
                    tokens: &mut TokenStream,
                    storage: &mut CellStorage)
                    -> Result<CellIndex, ParseError> {
-        if let Token::RightParen = try!(self.peek_tok(tokens)) {
+        if let Token::RightParen = self.peek_tok(tokens)? {
             Ok(NIL_INDEX)
         } else {
             self.parse_sexp(tokens, storage).and_then(|car| {
-                self.peek_tok(tokens)
-                    .and_then(|tok| {
-                        if let Token::Dot = tok {
-                            let _ = try!(self.next_tok(tokens));
+                self.skip_dot(tokens)
+                    .and_then(|dotted| {
+                        if dotted {
                             self.parse_sexp(tokens, storage)
                         } else {
                             self.parse_sexps(tokens, storage)
@@ -193,3 +358,119 @@ impl<'a> Parser<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_reports_consumed_length() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"(add 1 2) rest".to_vec();
+        let (_, consumed) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(consumed, 9);
+        assert_eq!(&input[consumed..], b" rest");
+    }
+
+    #[test]
+    fn token_stream_restores_checkpoints() {
+        let input = b"(add 1)".to_vec();
+        let mut tokens = TokenStream::new(&input);
+        assert_eq!(tokens.next_token().unwrap(), Token::LeftParen);
+
+        let checkpoint = tokens.checkpoint();
+        assert_eq!(tokens.next_token().unwrap(), Token::Symbol("add".to_string()));
+        assert_eq!(tokens.next_token().unwrap(), Token::Number("1".to_string()));
+        tokens.restore(checkpoint);
+        assert_eq!(tokens.next_token().unwrap(), Token::Symbol("add".to_string()));
+        assert_eq!(tokens.next_token().unwrap(), Token::Number("1".to_string()));
+
+        tokens.restore(checkpoint);
+        {
+            let mut spec = tokens.speculate();
+            spec.next_token().unwrap();
+            spec.next_token().unwrap();
+        }
+        assert_eq!(tokens.next_token().unwrap(), Token::Symbol("add".to_string()));
+        {
+            let mut spec = tokens.speculate();
+            assert_eq!(spec.next_token().unwrap(), Token::Number("1".to_string()));
+            spec.commit();
+        }
+        assert_eq!(tokens.next_token().unwrap(), Token::RightParen);
+    }
+
+    fn parse_str(input: &str,
+                 cells: &mut CellStorage,
+                 env: &mut Env)
+                 -> Result<CellIndex, ParseError> {
+        let buf = input.as_bytes().to_vec();
+        Parser::new(env).parse(&buf, cells).map(|(idx, _)| idx)
+    }
+
+    #[test]
+    fn dotted_forms_round_trip() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        for &(input, printed) in [("(1 2 . 3)", "(1 2 . 3)"),
+                                  ("(1 . (2 . 3))", "(1 2 . 3)"),
+                                  ("(1 . (2 3))", "(1 2 3)"),
+                                  ("(1 . ())", "(1)"),
+                                  ("((a . b) . (c . d))", "((a . b) c . d)"),
+                                  ("((1 . 2) (3 . 4))", "((1 . 2) (3 . 4))")]
+            .iter() {
+            let idx = parse_str(input, &mut cells, &mut env).unwrap();
+            assert_eq!(exp_to_string(idx, &cells, &env), printed);
+            let reparsed = parse_str(printed, &mut cells, &mut env).unwrap();
+            assert_eq!(dump_shape(idx, &cells), dump_shape(reparsed, &cells));
+        }
+    }
+
+    /// The structure of `idx` with cell indices left out.
+    fn dump_shape(idx: CellIndex, cells: &CellStorage) -> String {
+        match cells.val_of(idx) {
+            _ if idx == NIL_INDEX => "nil".to_string(),
+            CellType::Cons(head) => {
+                format!("[{} {}]", dump_shape(head, cells), dump_shape(cells.tail_of(idx), cells))
+            }
+            val => format!("{:?}", val),
+        }
+    }
+
+    #[test]
+    fn misplaced_dots_are_syntax_errors() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        for input in ["(1 . 2 3)", "(. 1)", "(1 . )", "."].iter() {
+            match parse_str(input, &mut cells, &mut env) {
+                Err(ParseError::SyntaxError(_)) => {}
+                other => panic!("expected a syntax error for {}, got {:?}", input, other),
+            }
+        }
+        match parse_str("(1 . 2", &mut cells, &mut env) {
+            Err(ParseError::UnexpectedEof(1)) => {}
+            other => panic!("expected an unfinished form, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+
+        let input = b"; leading\n(add 1 ; inline\n 2)".to_vec();
+        let (idx, _) = Parser::new(&mut env).parse(&input, &mut cells).unwrap();
+        assert_eq!(exp_to_string(idx, &cells, &env), "(add 1 2)");
+
+        assert!(is_blank(&b"  \n; only a comment\n\t".to_vec()));
+        assert!(!is_blank(&b" ; comment\nx".to_vec()));
+    }
+}
@@ -0,0 +1,144 @@
+use cell::*;
+use env::{DefaultNS, SymbolIndex};
+use eval::{EvalError, arithmetic, is_arithmetic};
+
+/// An instruction for the bytecode machine, which keeps a stack of
+/// numbers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Push(i32),
+    /// Pops the right and then the left operand and pushes the result of the
+    /// arithmetic builtin.
+    Arith(SymbolIndex),
+}
+
+/// A pending step for `compile`.
+enum CompileTask {
+    Exp(CellIndex),
+    Emit(Op),
+}
+
+/// Lowers `exp` to bytecode that computes the same number `eval` would.
+/// Only numbers and binary arithmetic on them can be compiled so far.
+pub fn compile(exp: CellIndex,
+               cells: &CellStorage,
+               ns: &DefaultNS)
+               -> Result<Vec<Op>, EvalError> {
+    let mut code = Vec::new();
+    let mut tasks = vec![CompileTask::Exp(exp)];
+    while let Some(task) = tasks.pop() {
+        match task {
+            CompileTask::Emit(op) => code.push(op),
+            CompileTask::Exp(exp) => {
+                match cells.val_of(exp) {
+                    CellType::Number(n) => code.push(Op::Push(n)),
+                    CellType::Cons(head) if is_binary(exp, cells) => {
+                        let op = match cells.val_of(head) {
+                            CellType::Symbol(op) if is_arithmetic(op, ns) => op,
+                            _ => return Err(EvalError::NotCompilable(exp)),
+                        };
+                        let lhs = car!(cdr!(exp, cells), cells);
+                        let rhs = car!(cdr!(cdr!(exp, cells), cells), cells);
+                        tasks.push(CompileTask::Emit(Op::Arith(op)));
+                        tasks.push(CompileTask::Exp(rhs));
+                        tasks.push(CompileTask::Exp(lhs));
+                    }
+                    _ => return Err(EvalError::NotCompilable(exp)),
+                }
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Runs `code` from `compile`, returning a cell holding the result. Code
+/// that doesn't leave exactly one number on the stack is a bug in the
+/// compiler and panics.
+pub fn run(code: &[Op],
+           cells: &mut CellStorage,
+           ns: &DefaultNS)
+           -> Result<CellIndex, EvalError> {
+    let mut stack = Vec::new();
+    for &op in code {
+        match op {
+            Op::Push(n) => stack.push(n),
+            Op::Arith(op) => {
+                let rhs = stack.pop().expect("bytecode stack underflow");
+                let lhs = stack.pop().expect("bytecode stack underflow");
+                stack.push(arithmetic(op, lhs, rhs, ns)?);
+            }
+        }
+    }
+    assert_eq!(stack.len(), 1, "bytecode left {} values", stack.len());
+    Ok(cells.alloc_cell(CellType::Number(stack[0])))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cell::{Cell, init_storage};
+    use env::Env;
+    use eval::eval;
+    use parser::Parser;
+
+    #[test]
+    fn compiled_matches_tree_walking() {
+        let mut buf = [Cell::empty(); 256];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for input in ["42",
+                      "(add 1 2)",
+                      "(sub 3 10)",
+                      "(add (mul (sub 10 4) (div 9 3)) (mod (add 17 5) (mul 2 3)))",
+                      "(modulo (sub 0 7) 3)",
+                      "(remainder (sub 0 7) 3)",
+                      "(mul (mul 1000 1000) 7)"]
+            .iter() {
+            let buf = input.as_bytes().to_vec();
+            let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+            let code = compile(exp, &cells, &ns).unwrap();
+            let compiled = run(&code, &mut cells, &ns).unwrap();
+            let walked = eval(exp, &mut cells, &mut env, &ns).unwrap();
+            assert_eq!(exp_to_string(compiled, &cells, &env),
+                       exp_to_string(walked, &cells, &env),
+                       "{}",
+                       input);
+        }
+    }
+
+    #[test]
+    fn compile_emits_postfix_code() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        let buf = b"(sub (mul 2 3) 4)".to_vec();
+        let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+        let expected = vec![Op::Push(2),
+                            Op::Push(3),
+                            Op::Arith(ns.mul),
+                            Op::Push(4),
+                            Op::Arith(ns.sub)];
+        assert_eq!(compile(exp, &cells, &ns).unwrap(), expected);
+    }
+
+    #[test]
+    fn only_arithmetic_compiles() {
+        let mut buf = [Cell::empty(); 64];
+        let mut cells = init_storage(&mut buf);
+        let mut env = Env::new();
+        let ns = DefaultNS::new(&mut env);
+
+        for input in ["(add 1 (hd ('(2))))", "(cons 1 2)", "(add 1 2 3)", "x"].iter() {
+            let buf = input.as_bytes().to_vec();
+            let (exp, _) = Parser::new(&mut env).parse(&buf, &mut cells).unwrap();
+            match compile(exp, &cells, &ns) {
+                Err(EvalError::NotCompilable(_)) => {}
+                other => panic!("expected {} not to compile, got {:?}", input, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn run_binary(input: &str) -> (Option<i32>, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_yetanotherlisp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn exit_ends_the_process() {
+    let (code, stdout) = run_binary("(add 1 2)\n(exit 3)\n(add 3 4)\n");
+    assert_eq!(code, Some(3));
+    assert!(stdout.contains("3\n"));
+    assert!(!stdout.contains("7"));
+
+    let (code, _) = run_binary("(exit)\n");
+    assert_eq!(code, Some(0));
+}